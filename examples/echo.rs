@@ -97,8 +97,9 @@ impl<T: EchoRpc + ::std::panic::RefUnwindSafe> conetty::Server for RpcServer<T>
                     }
                     Err(_) => {
                         // panic happened inside!
-                        Err(conetty::WireError::Status(
-                            "rpc panicked in server!".to_owned(),
+                        Err(conetty::WireError::status(
+                            conetty::StatusCode::INTERNAL,
+                            "rpc panicked in server!",
                         ))
                     }
                 }
@@ -112,8 +113,9 @@ impl<T: EchoRpc + ::std::panic::RefUnwindSafe> conetty::Server for RpcServer<T>
                     }
                     Err(_) => {
                         // panic happened inside!
-                        Err(conetty::WireError::Status(
-                            "rpc panicked in server!".to_owned(),
+                        Err(conetty::WireError::status(
+                            conetty::StatusCode::INTERNAL,
+                            "rpc panicked in server!",
                         ))
                     }
                 }
@@ -35,8 +35,9 @@ impl conetty::Server for RpcServer {
             match r_map.get(&req_id) {
                 Some(f) => *f,
                 None => {
-                    return Err(conetty::WireError::Status(
-                        "Service not available.".to_owned(),
+                    return Err(conetty::WireError::status(
+                        conetty::StatusCode::UNIMPLEMENTED,
+                        "Service not available.",
                     ))
                 }
             }
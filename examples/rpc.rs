@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use conetty::{MultiplexClient, TcpServer, WireError};
+
+conetty::service! {
+    /// rpc spec
+    pub mod echo_rpc {
+        fn echo(data: String) -> String;
+        fn add(x: u32, y: u32) -> u32;
+    }
+}
+
+struct Echo;
+
+// server implementation
+impl echo_rpc::Service for Echo {
+    fn echo(&self, data: String) -> Result<String, WireError> {
+        Ok(data)
+    }
+
+    fn add(&self, x: u32, y: u32) -> Result<u32, WireError> {
+        Ok(x + y)
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let addr = ("127.0.0.1", 4000);
+    let _server = echo_rpc::Server(Echo).start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_millis(100));
+    let client = echo_rpc::Client::new(client);
+
+    for i in 0..10 {
+        let s = format!("Hello World! id={i}");
+        let data = client.echo(s);
+        println!("recv = {data:?}");
+    }
+
+    for i in 0..10 {
+        let data = client.add(i, i);
+        println!("recv = {data:?}");
+    }
+}
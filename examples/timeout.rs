@@ -1,7 +1,7 @@
 use std::io::Write;
 use std::time::Duration;
 
-use conetty::{ReqBuf, RspBuf, Server, UdpClient, UdpServer, WireError};
+use conetty::{ReqBuf, RspBuf, Server, StatusCode, UdpClient, UdpServer, WireError};
 use may::coroutine;
 
 struct Echo;
@@ -11,7 +11,7 @@ impl Server for Echo {
         println!("req = {req:?}");
         coroutine::sleep(Duration::from_secs(1));
         // rsp.write_all(req).map_err(|e| WireError::ServerSerialize(e.to_string()))
-        Err(WireError::Status("timeout".to_owned()))
+        Err(WireError::status(StatusCode::DEADLINE_EXCEEDED, "timeout"))
     }
 }
 
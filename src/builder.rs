@@ -0,0 +1,290 @@
+use std::io;
+use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+use crate::frame::{FRAME_MAX_LEN, MSG_MAX_LEN};
+use crate::handshake::Features;
+use crate::layer::{Layer, Layers};
+use crate::server::{self, ServerInstance};
+use crate::Server;
+
+use may::coroutine;
+
+/// server options shared by all the transports
+#[derive(Debug, Clone)]
+pub(crate) struct ServerConfig {
+    // max number of alive connections
+    pub max_connections: Option<usize>,
+    // what to do with a new connection once max_connections is reached
+    pub connection_policy: ConnectionPolicy,
+    // max number of alive connections from the same ip
+    pub max_connections_per_ip: Option<usize>,
+    // max number of running requests for each connection
+    pub max_inflight: Option<usize>,
+    // max number of running requests of the whole server
+    pub max_inflight_total: Option<usize>,
+    // what to do with a new request once an inflight limit is reached
+    pub inflight_policy: InflightPolicy,
+    // max frame len of both request and response
+    pub max_frame_len: usize,
+    // max len of the message that is split into continuation frames
+    pub max_msg_len: usize,
+    // reject the request frames without a checksum
+    pub require_checksum: bool,
+    // the features offered to the clients
+    pub features: Features,
+    // timeout for reading the rest of a frame once it starts arriving
+    pub read_timeout: Option<Duration>,
+    // timeout for waiting the next frame on an idle connection
+    pub idle_timeout: Option<Duration>,
+    // timeout for writing the responses to a connection
+    pub write_timeout: Option<Duration>,
+    // the interval and timeout of the heartbeat
+    pub heartbeat: Option<(Duration, Duration)>,
+    // stack size of the service coroutines
+    pub stack_size: Option<usize>,
+    // listener backlog
+    pub backlog: Option<i32>,
+    // middleware of the unary requests
+    pub layers: Layers,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_connections: None,
+            connection_policy: ConnectionPolicy::default(),
+            max_connections_per_ip: None,
+            max_inflight: None,
+            max_inflight_total: None,
+            inflight_policy: InflightPolicy::default(),
+            max_frame_len: FRAME_MAX_LEN,
+            max_msg_len: MSG_MAX_LEN,
+            require_checksum: false,
+            features: Features::all(),
+            read_timeout: None,
+            idle_timeout: None,
+            write_timeout: None,
+            heartbeat: None,
+            stack_size: None,
+            backlog: None,
+            layers: Layers::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// the max message len of the responses, which are not split into the
+    /// continuation frames if the client doesn't support the chunking
+    pub fn rsp_msg_len(&self, features: Features) -> usize {
+        match features.contains(Features::CHUNKING) {
+            true => self.max_msg_len,
+            false => self.max_frame_len,
+        }
+    }
+
+    /// coroutine builder with the configured stack size
+    pub fn co_builder(&self, name: &str) -> coroutine::Builder {
+        let builder = coroutine::Builder::new().name(name.to_owned());
+        match self.stack_size {
+            Some(size) => builder.stack_size(size),
+            None => builder,
+        }
+    }
+}
+
+/// how the server handles a new connection once the max connections is reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionPolicy {
+    /// close the new connection immediately
+    #[default]
+    Reject,
+    /// stop accepting until an alive connection is closed,
+    /// the new connections are queued in the listen backlog
+    Wait,
+}
+
+/// how the server handles a new request once an inflight limit is reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InflightPolicy {
+    /// stop reading the connection until a running request is done,
+    /// the pressure is pushed back to the clients by the socket buffers.
+    /// while a duplex stream of the connection is open, its req items are
+    /// still read and the new requests wait in their own coroutines
+    #[default]
+    Backpressure,
+    /// reply the request with a `RESOURCE_EXHAUSTED` status immediately
+    Reject,
+}
+
+/// Server builder, configure the server and start it on any transport
+///
+/// ```no_run
+/// # use conetty::{RspBuf, Server, ServerBuilder, WireError};
+/// # struct Echo;
+/// # impl Server for Echo {
+/// #     fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+/// #         Ok(())
+/// #     }
+/// # }
+/// let _server = ServerBuilder::new(Echo)
+///     .max_connections(1024)
+///     .max_inflight(64)
+///     .start_tcp(("127.0.0.1", 4000))
+///     .unwrap();
+/// ```
+pub struct ServerBuilder<T: Server> {
+    server: T,
+    config: ServerConfig,
+}
+
+impl<T: Server> ServerBuilder<T> {
+    /// create a builder with default options for the server
+    pub fn new(server: T) -> Self {
+        ServerBuilder {
+            server,
+            config: ServerConfig::default(),
+        }
+    }
+
+    /// set the max number of alive connections
+    /// new connections are handled by the `connection_policy` once the limit is reached
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    /// set how to handle the new connections once the max connections is reached
+    /// the default is `ConnectionPolicy::Reject`
+    pub fn connection_policy(mut self, policy: ConnectionPolicy) -> Self {
+        self.config.connection_policy = policy;
+        self
+    }
+
+    /// set the max number of alive connections from the same ip
+    /// new connections from the ip would be rejected once the limit is reached
+    /// this is not applied to the unix domain socket and udp servers
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.config.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// set the max number of running requests for each connection
+    /// the new requests are handled by the `inflight_policy` once the limit is reached
+    /// for udp server this is applied to the whole server
+    pub fn max_inflight(mut self, max: usize) -> Self {
+        self.config.max_inflight = Some(max);
+        self
+    }
+
+    /// set the max number of running requests of the whole server
+    /// the new requests are handled by the `inflight_policy` once the limit is reached
+    pub fn max_inflight_total(mut self, max: usize) -> Self {
+        self.config.max_inflight_total = Some(max);
+        self
+    }
+
+    /// set how to handle the new requests once an inflight limit is reached
+    /// the default is `InflightPolicy::Backpressure`
+    pub fn inflight_policy(mut self, policy: InflightPolicy) -> Self {
+        self.config.inflight_policy = policy;
+        self
+    }
+
+    /// set the max frame len of both request and response
+    /// connections that send a frame longer than this would be closed,
+    /// longer responses are split into continuation frames
+    pub fn max_frame_len(mut self, max: usize) -> Self {
+        self.config.max_frame_len = max;
+        self
+    }
+
+    /// set the max message len of both request and response
+    /// this bounds the memory of reassembling the continuation frames,
+    /// connections that send a message longer than this would be closed
+    pub fn max_message_len(mut self, max: usize) -> Self {
+        self.config.max_msg_len = max;
+        self
+    }
+
+    /// reject the requests that don't carry a checksum with an `INVALID_ARGUMENT` status,
+    /// the requests with a mismatched checksum are always replied with `DATA_LOSS`
+    pub fn require_checksum(mut self, require: bool) -> Self {
+        self.config.require_checksum = require;
+        self
+    }
+
+    /// set the features offered to the clients, the default is `Features::all()`
+    /// the requests that use a feature not negotiated are rejected with an
+    /// `INVALID_ARGUMENT` status, the udp clients are checked against them directly
+    pub fn features(mut self, features: Features) -> Self {
+        self.config.features = features;
+        self
+    }
+
+    /// set the timeout for reading the rest of a frame once it starts arriving,
+    /// connections that don't send the whole frame within it would be closed
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    /// set the timeout for waiting the next frame,
+    /// connections that stay idle longer than this would be closed
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// set the timeout for writing the responses,
+    /// connections that can't take the responses within this would be closed
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// ping the clients on each interval, the connections that
+    /// receive nothing from the client within the timeout would be closed
+    /// only the clients that negotiated the `HEARTBEAT` feature are pinged
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.config.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// set the stack size of the service coroutines
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.config.stack_size = Some(size);
+        self
+    }
+
+    /// set the listen backlog of the stream listener
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.config.backlog = Some(backlog);
+        self
+    }
+
+    /// add a middleware layer that wraps the service of the requests, including the streams
+    /// the first added layer is the outermost one
+    pub fn layer<L: Layer>(mut self, layer: L) -> Self {
+        self.config.layers.push(layer);
+        self
+    }
+
+    /// Spawns the tcp service, binding to the given address
+    pub fn start_tcp<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerInstance> {
+        server::start_tcp(self.server, self.config, addr)
+    }
+
+    /// Spawns the udp service, binding to the given address
+    pub fn start_udp<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerInstance> {
+        server::start_udp(self.server, self.config, addr)
+    }
+
+    /// Spawns the unix domain socket service, binding to the given path
+    #[cfg(unix)]
+    pub fn start_uds<P: AsRef<Path>>(self, path: P) -> io::Result<ServerInstance> {
+        server::start_uds(self.server, self.config, path)
+    }
+}
@@ -0,0 +1,242 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::{Error, StatusCode};
+use crate::frame::{Frame, ReqBuf};
+use crate::metadata::Metadata;
+use crate::router::METHOD_KEY;
+use crate::Client;
+
+use may::coroutine;
+
+/// client middleware that wraps `Client::call_service`
+///
+/// a layer gets the req before calling `next.call_service`, and the rsp frame or
+/// the error after. the layered client still implements `Client`, so the layers
+/// can be stacked by `ClientExt::with_layer`, the last added layer is the outermost one
+///
+/// closures with the same signature as `call` are also layers
+pub trait ClientLayer: Send + Sync + 'static {
+    /// handle the req, call `next.call_service` to pass it to the inner client
+    fn call(&self, req: ReqBuf, next: &dyn Client) -> Result<Frame, Error>;
+}
+
+impl<F> ClientLayer for F
+where
+    F: Fn(ReqBuf, &dyn Client) -> Result<Frame, Error> + Send + Sync + 'static,
+{
+    fn call(&self, req: ReqBuf, next: &dyn Client) -> Result<Frame, Error> {
+        self(req, next)
+    }
+}
+
+/// a client wrapped by a layer, created by `ClientExt::with_layer`
+pub struct Layered<C, L> {
+    inner: C,
+    layer: L,
+}
+
+impl<C: Client, L: ClientLayer> Layered<C, L> {
+    pub fn new(inner: C, layer: L) -> Self {
+        Layered { inner, layer }
+    }
+
+    /// the wrapped client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Client, L: ClientLayer> Client for Layered<C, L> {
+    fn call_service(&self, req: ReqBuf) -> Result<Frame, Error> {
+        self.layer.call(req, &self.inner)
+    }
+}
+
+/// retry the failed calls
+///
+/// by default the io errors and the `UNAVAILABLE` status are retried,
+/// the status replied by the server is checked from the rsp frame
+pub struct Retry {
+    max_retries: usize,
+    backoff: Duration,
+    retry_if: fn(&Error) -> bool,
+}
+
+impl Retry {
+    /// retry at most `max_retries` times
+    pub fn new(max_retries: usize) -> Self {
+        Retry {
+            max_retries,
+            backoff: Duration::ZERO,
+            retry_if: Retry::default_retry_if,
+        }
+    }
+
+    /// set the wait time before each retry, the time is doubled for the next retry
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// set the errors that should be retried
+    pub fn retry_if(mut self, f: fn(&Error) -> bool) -> Self {
+        self.retry_if = f;
+        self
+    }
+
+    fn default_retry_if(e: &Error) -> bool {
+        match e {
+            // the rsp wait timeout is not retried, the server may still run the req
+            Error::Io(e) => e.kind() != io::ErrorKind::TimedOut,
+            Error::Status { code, .. } => *code == StatusCode::UNAVAILABLE,
+            _ => false,
+        }
+    }
+}
+
+impl ClientLayer for Retry {
+    fn call(&self, req: ReqBuf, next: &dyn Client) -> Result<Frame, Error> {
+        let mut backoff = self.backoff;
+        let mut retries = 0;
+        loop {
+            let ret = next.call_service(req.clone());
+            let retry = match ret {
+                Ok(ref frame) => frame
+                    .decode_rsp()
+                    .err()
+                    .is_some_and(|e| (self.retry_if)(&e)),
+                Err(ref e) => (self.retry_if)(e),
+            };
+            if !retry || retries == self.max_retries {
+                return ret;
+            }
+            retries += 1;
+            warn!("retry the request, retries={retries}");
+            if !backoff.is_zero() {
+                coroutine::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+/// set the timeout of the reqs that don't have their own timeout
+pub struct DefaultTimeout(pub Duration);
+
+impl ClientLayer for DefaultTimeout {
+    fn call(&self, mut req: ReqBuf, next: &dyn Client) -> Result<Frame, Error> {
+        if req.timeout().is_none() {
+            req.set_timeout(Some(self.0));
+        }
+        next.call_service(req)
+    }
+}
+
+/// insert the metadata into each req, the existing keys of the req are kept
+pub struct DefaultHeaders(pub Metadata);
+
+impl ClientLayer for DefaultHeaders {
+    fn call(&self, mut req: ReqBuf, next: &dyn Client) -> Result<Frame, Error> {
+        for (k, v) in self.0.iter() {
+            if req.metadata_mut().get(k).is_none() {
+                req.metadata_mut().insert(k, v);
+            }
+        }
+        next.call_service(req)
+    }
+}
+
+/// return true if the call failed, including the error status replied by the server
+fn is_failed(ret: &Result<Frame, Error>) -> bool {
+    match ret {
+        Ok(frame) => frame.decode_rsp().is_err(),
+        Err(_) => true,
+    }
+}
+
+/// the counters of the calls passed a `Metrics` layer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientMetrics {
+    /// calls that are made
+    pub calls: u64,
+    /// calls that failed or are replied with an error
+    pub errors: u64,
+    /// total time of the calls
+    pub latency: Duration,
+}
+
+#[derive(Debug, Default)]
+struct MetricsCounters {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    // in microseconds
+    latency: AtomicU64,
+}
+
+/// count the calls, the failed ones and their latency
+///
+/// the counters are shared by the clones, keep a clone to read them
+/// after the layer is added to the client
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<MetricsCounters>);
+
+impl Metrics {
+    /// create the layer with zero counters
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// the current counters
+    pub fn get(&self) -> ClientMetrics {
+        ClientMetrics {
+            calls: self.0.calls.load(Ordering::Relaxed),
+            errors: self.0.errors.load(Ordering::Relaxed),
+            latency: Duration::from_micros(self.0.latency.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl ClientLayer for Metrics {
+    fn call(&self, req: ReqBuf, next: &dyn Client) -> Result<Frame, Error> {
+        let start = Instant::now();
+        let ret = next.call_service(req);
+        let latency = start.elapsed().as_micros() as u64;
+        self.0.calls.fetch_add(1, Ordering::Relaxed);
+        self.0.latency.fetch_add(latency, Ordering::Relaxed);
+        if is_failed(&ret) {
+            self.0.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        ret
+    }
+}
+
+/// log each call and its result by the `log` crate
+pub struct Logging;
+
+impl ClientLayer for Logging {
+    fn call(&self, mut req: ReqBuf, next: &dyn Client) -> Result<Frame, Error> {
+        let method = match req.metadata_mut().get(METHOD_KEY) {
+            Some(v) => String::from_utf8_lossy(v).into_owned(),
+            None => "-".to_owned(),
+        };
+        info!("call start: method={method}");
+        let start = Instant::now();
+        let ret = next.call_service(req);
+        let elapsed = start.elapsed();
+        match ret {
+            Ok(ref frame) => match frame.decode_rsp() {
+                Ok(_) => info!("call done: method={method}, elapsed={elapsed:?}"),
+                Err(e) => warn!("call failed: method={method}, elapsed={elapsed:?}, err = {e}"),
+            },
+            Err(ref e) => warn!("call failed: method={method}, elapsed={elapsed:?}, err = {e}"),
+        }
+        ret
+    }
+}
@@ -0,0 +1,172 @@
+use std::error::Error as StdError;
+use std::io::Write;
+use std::marker::PhantomData;
+
+use crate::client_layer::{ClientLayer, Layered};
+use crate::errors::{Error, WireError};
+use crate::frame::{ReqBuf, RspBuf};
+use crate::{Client, Server};
+
+/// the error returned by a codec
+pub type CodecError = Box<dyn StdError + Send + Sync>;
+
+/// serialize and deserialize the typed requests and responses
+///
+/// the codecs are unit structs passed by value, like `client.call_with(Json, &req)`
+pub trait Codec<T>: Send + Sync + 'static {
+    /// encode the value into the writer
+    fn encode<W: Write>(&self, value: &T, w: W) -> Result<(), CodecError>;
+
+    /// decode the value from the buf
+    fn decode(&self, buf: &[u8]) -> Result<T, CodecError>;
+}
+
+/// the bincode codec for any serde type
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Bincode {
+    fn encode<W: Write>(&self, value: &T, w: W) -> Result<(), CodecError> {
+        bincode::serialize_into(w, value).map_err(Into::into)
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(buf).map_err(Into::into)
+    }
+}
+
+/// the json codec for any serde type
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Json {
+    fn encode<W: Write>(&self, value: &T, w: W) -> Result<(), CodecError> {
+        serde_json::to_writer(w, value).map_err(Into::into)
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(buf).map_err(Into::into)
+    }
+}
+
+/// the raw bytes codec, the bytes are sent as is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode<W: Write>(&self, value: &Vec<u8>, mut w: W) -> Result<(), CodecError> {
+        w.write_all(value).map_err(Into::into)
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(buf.to_vec())
+    }
+}
+
+/// encode the typed req by the codec
+pub(crate) fn encode_req<C: Codec<T>, T>(codec: &C, req: &T) -> Result<ReqBuf, Error> {
+    let mut buf = ReqBuf::new();
+    codec
+        .encode(req, &mut buf)
+        .map_err(|e| Error::ClientSerialize(e.to_string()))?;
+    Ok(buf)
+}
+
+/// decode the typed rsp by the codec
+pub(crate) fn decode_rsp<C: Codec<T>, T>(codec: &C, rsp: &[u8]) -> Result<T, Error> {
+    codec
+        .decode(rsp)
+        .map_err(|e| Error::ClientDeserialize(e.to_string()))
+}
+
+/// decode the typed req by the codec in the server
+pub(crate) fn decode_req<C: Codec<T>, T>(codec: &C, req: &[u8]) -> Result<T, WireError> {
+    codec
+        .decode(req)
+        .map_err(|e| WireError::ServerDeserialize(e.to_string()))
+}
+
+/// encode the typed rsp by the codec in the server
+pub(crate) fn encode_rsp<C: Codec<T>, T>(
+    codec: &C,
+    rsp: &mut RspBuf,
+    value: &T,
+) -> Result<(), WireError> {
+    codec
+        .encode(value, rsp)
+        .map_err(|e| WireError::ServerSerialize(e.to_string()))
+}
+
+/// typed call helpers and layers for any `Client`
+pub trait ClientExt: Client {
+    /// wrap the client with the layer, the layered client is still a `Client`
+    fn with_layer<L: ClientLayer>(self, layer: L) -> Layered<Self, L>
+    where
+        Self: Sized,
+    {
+        Layered::new(self, layer)
+    }
+
+    /// call the server with the typed req, which is encoded by the codec
+    /// the rsp is decoded by the same codec
+    fn call_with<C, Req, Rsp>(&self, codec: C, req: &Req) -> Result<Rsp, Error>
+    where
+        C: Codec<Req> + Codec<Rsp>,
+    {
+        let frame = self.call_service(encode_req(&codec, req)?)?;
+        decode_rsp(&codec, frame.decode_rsp()?)
+    }
+
+    /// call the server with the typed req, encoded by bincode
+    #[cfg(feature = "bincode")]
+    fn call<Req, Rsp>(&self, req: &Req) -> Result<Rsp, Error>
+    where
+        Req: serde::Serialize + serde::de::DeserializeOwned,
+        Rsp: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        self.call_with(Bincode, req)
+    }
+}
+
+impl<T: Client + ?Sized> ClientExt for T {}
+
+/// a `Server` that decodes the req into `Req` and encodes the returned `Rsp` by the codec
+///
+/// the codec failures are reported as `ServerDeserialize` and `ServerSerialize` errors
+pub struct TypedServer<C, Req, Rsp, F> {
+    codec: C,
+    f: F,
+    _marker: PhantomData<fn(Req) -> Rsp>,
+}
+
+impl<C, Req, Rsp, F> TypedServer<C, Req, Rsp, F>
+where
+    C: Codec<Req> + Codec<Rsp>,
+    F: Fn(Req) -> Result<Rsp, WireError> + Send + Sync + 'static,
+{
+    pub fn new(codec: C, f: F) -> Self {
+        TypedServer {
+            codec,
+            f,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<C, Req, Rsp, F> Server for TypedServer<C, Req, Rsp, F>
+where
+    C: Codec<Req> + Codec<Rsp>,
+    F: Fn(Req) -> Result<Rsp, WireError> + Send + Sync + 'static,
+    Req: 'static,
+    Rsp: 'static,
+{
+    fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+        let req = decode_req(&self.codec, req)?;
+        let ret = (self.f)(req)?;
+        encode_rsp(&self.codec, rsp, &ret)
+    }
+}
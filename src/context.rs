@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::frame::Frame;
+use crate::handshake::Features;
+use crate::metadata::Metadata;
+
+/// credentials of the peer process on a unix domain socket
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    /// process id of the peer, not available on all platforms
+    pub pid: Option<i32>,
+    /// user id of the peer
+    pub uid: u32,
+    /// group id of the peer
+    pub gid: u32,
+}
+
+/// the peer of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+    /// address of the tcp or udp peer
+    Inet(SocketAddr),
+    /// credentials of the unix domain socket peer
+    #[cfg(unix)]
+    Unix(PeerCred),
+    /// the peer is not available
+    Unknown,
+}
+
+/// the context of a request, passed to `Server::service_with_context` and the streaming variants
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    id: u64,
+    conn_id: Option<u64>,
+    peer: Peer,
+    features: Features,
+    deadline: Option<Instant>,
+    metadata: Metadata,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RequestContext {
+    pub(crate) fn new(
+        req: &Frame,
+        conn_id: Option<u64>,
+        peer: Peer,
+        features: Features,
+        cancelled: Arc<AtomicBool>,
+    ) -> Self {
+        RequestContext {
+            id: req.id,
+            conn_id,
+            peer,
+            features,
+            deadline: req.deadline(),
+            metadata: req.metadata().clone(),
+            cancelled,
+        }
+    }
+
+    /// the frame id of the request
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// the id of the connection that the request comes from
+    /// it's unique in the server, none for udp server
+    pub fn conn_id(&self) -> Option<u64> {
+        self.conn_id
+    }
+
+    /// the peer that sends the request
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// the features negotiated with the client of the connection
+    /// the features of the server for udp, which has no handshake
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// the deadline of the request, if the client set a timeout
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// the remaining time before the deadline, zero if it's already expired
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// return true if the deadline is expired
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// the metadata sent by the client along with the request
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// return true if the client cancelled the request
+    ///
+    /// the service coroutine is cancelled at its next blocking point,
+    /// a long computation without blocking can check this to stop early
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
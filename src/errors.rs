@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io;
 
 use thiserror::Error;
@@ -37,9 +38,49 @@ pub enum Error {
     Timeout,
     /// The server returns an status error due to different reasons.
     ///
-    /// Typically this indicates that the server is not healthy
-    #[error("The server returns an status error due to different reasons: {0}")]
-    Status(String),
+    /// The code tells the reason, like `StatusCode::UNAVAILABLE` when the server is not healthy
+    #[error("The server returns an status error, code={code}, message={message}")]
+    Status {
+        code: StatusCode,
+        message: String,
+        details: Vec<u8>,
+    },
+    /// The frame checksum doesn't match the received data.
+    ///
+    /// Typically this indicates the data is corrupted on the wire
+    #[error("frame checksum mismatch, id={id}, expected={expected:#010x}, actual={actual:#010x}")]
+    Checksum { id: u64, expected: u32, actual: u32 },
+    /// The frame length exceeds the max frame len.
+    ///
+    /// You can set the max frame len in the client and server instance
+    #[error("frame too large, len={len}, max={max}")]
+    FrameTooLarge { len: usize, max: usize },
+    /// The message length exceeds the max message len.
+    ///
+    /// A message is split into continuation frames when it's longer than the
+    /// max frame len, you can set the max message len in the client and server instance
+    #[error("message too large, len={len}, max={max}")]
+    MessageTooLarge { len: usize, max: usize },
+    /// The peer speaks a different protocol version.
+    ///
+    /// Typically this indicates the client and server are built with incompatible versions
+    #[error("protocol version mismatch, local={local}, remote={remote}")]
+    VersionMismatch { local: u8, remote: u8 },
+    /// The connection preface is invalid.
+    ///
+    /// Typically this indicates the peer is not a conetty endpoint
+    #[error("invalid connection preface: {0}")]
+    Handshake(String),
+    /// The request is cancelled by the client.
+    ///
+    /// The server would abandon the running service of the request
+    #[error("The request is cancelled by the client")]
+    Cancelled,
+    /// The method id of the name collides with another registered route.
+    ///
+    /// The method ids are hashes of the names, rename one of the routes
+    #[error("method id conflict, name={name}, other={other}")]
+    MethodIdConflict { name: String, other: String },
 }
 
 /// A serializable, server-supplied error.
@@ -51,11 +92,72 @@ pub enum WireError {
     #[error("Serializing server response: {0}")]
     ServerSerialize(String),
     /// Server Status
-    #[error("Server Status: {0}")]
-    Status(String),
+    #[error("Server Status: code={code}, message={message}")]
+    Status {
+        code: StatusCode,
+        message: String,
+        details: Vec<u8>,
+    },
     /// Server polling
     /// this is a special error code that used for server polling request from client
     /// client will first check this code in the very beginning before return to client rpc call
     #[error("Server polling")]
     Polling,
 }
+
+impl WireError {
+    /// create a status error without details
+    pub fn status<S: Into<String>>(code: StatusCode, message: S) -> Self {
+        WireError::Status {
+            code,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+}
+
+/// The numeric code of a status error
+///
+/// The well-known codes are used by the framework itself, the other values
+/// are free for the services to define their own errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StatusCode(pub u32);
+
+impl StatusCode {
+    /// The error is not in any other code
+    pub const UNKNOWN: StatusCode = StatusCode(2);
+    /// The request is invalid
+    pub const INVALID_ARGUMENT: StatusCode = StatusCode(3);
+    /// The request exceeds the deadline set by the client
+    pub const DEADLINE_EXCEEDED: StatusCode = StatusCode(4);
+    /// The requested entity is not found
+    pub const NOT_FOUND: StatusCode = StatusCode(5);
+    /// The server runs out of some resource, like the in-flight requests
+    pub const RESOURCE_EXHAUSTED: StatusCode = StatusCode(8);
+    /// The request is not supported by the server
+    pub const UNIMPLEMENTED: StatusCode = StatusCode(12);
+    /// The server is broken internally
+    pub const INTERNAL: StatusCode = StatusCode(13);
+    /// The server is not available now, like shutting down, the client may retry later
+    pub const UNAVAILABLE: StatusCode = StatusCode(14);
+    /// The data is lost or corrupted, like a frame with a mismatched checksum
+    pub const DATA_LOSS: StatusCode = StatusCode(15);
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            StatusCode::UNKNOWN => "unknown",
+            StatusCode::INVALID_ARGUMENT => "invalid argument",
+            StatusCode::DEADLINE_EXCEEDED => "deadline exceeded",
+            StatusCode::NOT_FOUND => "not found",
+            StatusCode::RESOURCE_EXHAUSTED => "resource exhausted",
+            StatusCode::UNIMPLEMENTED => "unimplemented",
+            StatusCode::INTERNAL => "internal",
+            StatusCode::UNAVAILABLE => "unavailable",
+            StatusCode::DATA_LOSS => "data loss",
+            StatusCode(code) => return write!(f, "{code}"),
+        };
+        write!(f, "{} ({name})", self.0)
+    }
+}
@@ -1,44 +1,222 @@
-use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::io::{self, Cursor, Read, Write};
+use std::time::{Duration, Instant};
 
+use crate::errors::StatusCode;
+use crate::handshake::Features;
+use crate::metadata::Metadata;
+use crate::router::{METHOD_ID_KEY, METHOD_KEY};
 use crate::{Error, WireError};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 
 // Frame layout
-// id(u64) + len(u64) + payload([u8; len])
+// id(u64) + len(u64) + payload([u8; len]) + [crc(u32)]
 
 // req frame layout
-// id(u64) + len(u64) + req_data([u8; len])
+// id(u64) + len(u64) + [timeout(u64)] + [metadata] + req_data([u8; len])
 
-// rsp frame layout
-// id(u64) + len(u64) + ty(u8) + len1(u64) + rsp_data([u8; len1])
+// the timeout is the remaining time of the client in microseconds,
+// present only when the deadline flag is set.
+// the metadata section is present only when the headers flag is set
 
-// max frame len
-const FRAME_MAX_LEN: u64 = 1024 * 1024;
+// rsp frame layout
+// id(u64) + len(u64) + [metadata] + ty(u8) + len1(u64) + rsp_data([u8; len1])
+
+// a streaming rsp is a sequence of rsp frames with the same id
+// item(ty=4) * n + end(ty=5), or terminated by an error rsp(ty=1..3)
+
+// a streaming req is a sequence of req frames with the same id and the duplex flag
+// item * n + end(with the end flag and no data)
+
+// the high byte of the len field is used as frame flags
+// flags(u8) + len(u56)
+
+// a message longer than the max frame len is split into continuation frames
+// with the same id and flags, all but the last one are marked with the more flag.
+// the continuation frames are always written together, and reassembled into one
+// frame when decoding
+
+/// default max frame len, including the frame head
+pub const FRAME_MAX_LEN: usize = 1024 * 1024;
+/// default max message len, the total len of the reassembled continuation frames
+pub const MSG_MAX_LEN: usize = 16 * 1024 * 1024;
+/// max len of a udp datagram, both the request and the response
+pub(crate) const UDP_MAX_LEN: usize = 64 * 1024;
+// mask of the real length in the len field
+const LEN_MASK: u64 = (1 << 56) - 1;
+// the frame is followed by a crc32c of the header and payload
+const FLAG_CHECKSUM: u8 = 0x80;
+// the frame is a control frame, the payload is the control type(u8)
+const FLAG_CONTROL: u8 = 0x40;
+// the req expects a streaming rsp
+const FLAG_STREAM: u8 = 0x20;
+// the req is an item of a streaming req
+const FLAG_DUPLEX: u8 = 0x10;
+// the end of a streaming req
+const FLAG_END: u8 = 0x08;
+// more continuation frames of the same message follow
+const FLAG_MORE: u8 = 0x04;
+// the req head is followed by the timeout of the client
+const FLAG_DEADLINE: u8 = 0x02;
+// the head is followed by the metadata section
+const FLAG_HEADERS: u8 = 0x01;
+
+/// rsp type of a streaming rsp item, more frames would follow
+pub(crate) const RSP_STREAM_ITEM: u8 = 4;
+/// rsp type of the end of a streaming rsp
+pub(crate) const RSP_STREAM_END: u8 = 5;
+
+/// control frames used by the framework itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Control {
+    /// the server is shutting down, no new requests would be served
+    GoAway = 1,
+    /// the client abandons the request with the same id
+    Cancel = 2,
+    /// the heartbeat, the peer replies a pong with the same id
+    Ping = 3,
+    /// the reply of the ping
+    Pong = 4,
+}
 
 /// raw frame wrapper, low level protocol
-/// TODO: add check sum check
 #[derive(Debug)]
 pub struct Frame {
     /// frame id, req and rsp has the same id
     pub id: u64,
+    /// frame flags
+    flags: u8,
     /// payload data
     data: Vec<u8>,
+    /// len of the frame head, including the head extensions
+    head_len: usize,
+    /// the deadline of the req, calculated when decoded
+    deadline: Option<Instant>,
+    /// the metadata of the frame
+    metadata: Metadata,
+    /// the frame is reassembled from continuation frames
+    chunked: bool,
 }
 
 impl Frame {
     /// decode a frame from the reader
-    pub fn decode_from<R: Read>(r: &mut R) -> io::Result<Self> {
+    ///
+    /// if the frame carries a checksum it's verified here, a mismatch
+    /// is reported as `Error::Checksum` after the whole frame is consumed
+    pub fn decode_from<R: Read>(r: &mut R) -> Result<Self, Error> {
+        Self::decode_from_with_limit(r, FRAME_MAX_LEN)
+    }
+
+    /// decode a frame from the reader, frames longer than `max_len` are rejected
+    /// with `Error::FrameTooLarge` before reading the payload
+    pub fn decode_from_with_limit<R: Read>(r: &mut R, max_len: usize) -> Result<Self, Error> {
+        Self::decode_from_with_limits(r, max_len, max_len.max(MSG_MAX_LEN))
+    }
+
+    /// decode a frame from the reader, the continuation frames are reassembled into one
+    ///
+    /// frames longer than `max_len` are rejected with `Error::FrameTooLarge`, and
+    /// messages longer than `max_msg_len` are rejected with `Error::MessageTooLarge`
+    pub fn decode_from_with_limits<R: Read>(
+        r: &mut R,
+        max_len: usize,
+        max_msg_len: usize,
+    ) -> Result<Self, Error> {
+        let (mut frame, mut mismatch) = Self::read_frame(r, max_len)?;
+        let chunked = frame.flags & FLAG_MORE != 0;
+
+        while frame.flags & FLAG_MORE != 0 {
+            let (next, next_mismatch) = Self::read_frame(r, max_len)?;
+            if next.id != frame.id {
+                error!("interleaved continuation frame, id={}", next.id);
+                let e =
+                    io::Error::new(io::ErrorKind::InvalidData, "interleaved continuation frame");
+                return Err(e.into());
+            }
+
+            let len = frame.data.len() + next.data.len() - 16;
+            if len > max_msg_len {
+                error!("decode too big message length. len={len}, max={max_msg_len}");
+                return Err(Error::MessageTooLarge {
+                    len,
+                    max: max_msg_len,
+                });
+            }
+
+            frame.data.extend_from_slice(&next.data[16..]);
+            frame.flags = (frame.flags & !FLAG_MORE) | (next.flags & FLAG_MORE);
+            // consume the whole message before reporting the mismatch
+            mismatch = mismatch.or(next_mismatch);
+        }
+
+        if let Some((expected, actual)) = mismatch {
+            error!("frame checksum mismatch, id={}", frame.id);
+            return Err(Error::Checksum {
+                id: frame.id,
+                expected,
+                actual,
+            });
+        }
+
+        // the frame that is not chunked is bounded by the message len too
+        if !chunked && frame.data.len() - 16 > max_msg_len {
+            let len = frame.data.len() - 16;
+            error!("decode too big message length. len={len}, max={max_msg_len}");
+            return Err(Error::MessageTooLarge {
+                len,
+                max: max_msg_len,
+            });
+        }
+
+        if chunked {
+            frame.chunked = true;
+            // the len field of the reassembled frame
+            let len = (frame.data.len() - 16) as u64 | (u64::from(frame.flags) << 56);
+            frame.data[8..16].copy_from_slice(&len.to_be_bytes());
+        }
+        frame.decode_head()?;
+        Ok(frame)
+    }
+
+    /// decode the head extensions that follow the id and len
+    fn decode_head(&mut self) -> Result<(), Error> {
+        if self.flags & FLAG_DEADLINE != 0 {
+            let timeout = match self.data.get(16..24) {
+                Some(b) => u64::from_be_bytes(b.try_into().unwrap()),
+                None => {
+                    let e = io::Error::new(io::ErrorKind::InvalidData, "invalid frame timeout");
+                    return Err(e.into());
+                }
+            };
+            // a timeout too large to be a deadline is taken as no deadline
+            self.deadline = Instant::now().checked_add(Duration::from_micros(timeout));
+            self.head_len = 24;
+        }
+        if self.flags & FLAG_HEADERS != 0 {
+            let (metadata, len) = Metadata::decode(&self.data[self.head_len..])?;
+            self.metadata = metadata;
+            self.head_len += len;
+        }
+        Ok(())
+    }
+
+    /// read one frame from the reader, the checksum mismatch is returned
+    /// along with the frame so that the following frames can still be consumed
+    fn read_frame<R: Read>(r: &mut R, max_len: usize) -> Result<(Self, Option<(u32, u32)>), Error> {
         use std::mem::MaybeUninit;
         let id = r.read_u64::<BigEndian>()?;
         info!("decode id = {:?}", id);
 
-        let len = r.read_u64::<BigEndian>()? + 16;
+        let raw_len = r.read_u64::<BigEndian>()?;
+        let flags = (raw_len >> 56) as u8;
+        let len = (raw_len & LEN_MASK) + 16;
         info!("decode len = {:?}", len);
 
-        if len > FRAME_MAX_LEN {
-            let s = format!("decode too big frame length. len={len}");
-            error!("{s}");
-            return Err(io::Error::new(ErrorKind::InvalidInput, s));
+        if len > max_len as u64 {
+            error!("decode too big frame length. len={len}, max={max_len}");
+            return Err(Error::FrameTooLarge {
+                len: len as usize,
+                max: max_len,
+            });
         }
 
         let mut data = MaybeUninit::new(Vec::with_capacity(len as usize));
@@ -52,28 +230,110 @@ impl Frame {
         // blow can be skipped, we don't need them in the buffer
         let mut cursor = Cursor::new(data);
         cursor.write_u64::<BigEndian>(id).unwrap();
-        cursor.write_u64::<BigEndian>(len - 16).unwrap();
+        cursor.write_u64::<BigEndian>(raw_len).unwrap();
         let data = cursor.into_inner();
 
-        Ok(Frame { id, data })
+        let mut mismatch = None;
+        if flags & FLAG_CHECKSUM != 0 {
+            let expected = r.read_u32::<BigEndian>()?;
+            let actual = crc32c::crc32c(&data);
+            if expected != actual {
+                mismatch = Some((expected, actual));
+            }
+        }
+
+        let frame = Frame {
+            id,
+            flags,
+            data,
+            head_len: 16,
+            deadline: None,
+            metadata: Metadata::new(),
+            chunked: false,
+        };
+        Ok((frame, mismatch))
+    }
+
+    /// return true if the frame carried a verified checksum
+    pub fn has_checksum(&self) -> bool {
+        self.flags & FLAG_CHECKSUM != 0
+    }
+
+    /// return true if this is a control frame that is not a response to any request
+    pub fn is_control(&self) -> bool {
+        self.flags & FLAG_CONTROL != 0
+    }
+
+    /// the protocol features used by the frame
+    pub(crate) fn features(&self) -> Features {
+        let mut features = Features::empty();
+        if self.flags & FLAG_CHECKSUM != 0 {
+            features = features | Features::CHECKSUM;
+        }
+        if self.chunked {
+            features = features | Features::CHUNKING;
+        }
+        if self.flags & FLAG_DEADLINE != 0 {
+            features = features | Features::DEADLINE;
+        }
+        if self.flags & FLAG_HEADERS != 0 {
+            features = features | Features::METADATA;
+        }
+        if self.flags & (FLAG_STREAM | FLAG_DUPLEX) != 0 {
+            features = features | Features::STREAMING;
+        }
+        features
+    }
+
+    /// return true if the req expects a streaming rsp
+    pub(crate) fn is_stream(&self) -> bool {
+        self.flags & FLAG_STREAM != 0
+    }
+
+    /// return true if the req is part of a streaming req
+    pub(crate) fn is_duplex(&self) -> bool {
+        self.flags & FLAG_DUPLEX != 0
     }
 
-    /// convert self into raw buf that can be re-send as a frame
-    // pub fn finish(self, id: u64) -> Vec<u8> {
-    //     let mut cursor = Cursor::new(self.data);
+    /// return true if this is the end of a streaming req
+    pub(crate) fn is_end(&self) -> bool {
+        self.flags & FLAG_END != 0
+    }
+
+    /// return the deadline of the req, if the client set a timeout
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
 
-    //     // write from start
-    //     cursor.write_u64::<BigEndian>(id).unwrap();
-    //     info!("re-encode id = {:?}", id);
+    /// return the metadata of the frame
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
 
-    //     cursor.into_inner()
-    // }
+    /// return the rsp type of a rsp frame
+    pub(crate) fn rsp_type(&self) -> Option<u8> {
+        self.data.get(self.head_len).copied()
+    }
+
+    /// decode the control type of a control frame
+    pub(crate) fn control(&self) -> Option<Control> {
+        if !self.is_control() {
+            return None;
+        }
+        match self.data.get(self.head_len) {
+            Some(1) => Some(Control::GoAway),
+            Some(2) => Some(Control::Cancel),
+            Some(3) => Some(Control::Ping),
+            Some(4) => Some(Control::Pong),
+            _ => None,
+        }
+    }
 
     /// decode a request from the frame, this would return the req raw buffer
     /// you need to deserialized from it into the real type
     pub fn decode_req(&self) -> &[u8] {
         // skip the frame head
-        &self.data[16..]
+        &self.data[self.head_len..]
     }
 
     /// decode a response from the frame, this would return the rsp raw buffer
@@ -83,25 +343,31 @@ impl Frame {
 
         let mut r = Cursor::new(&self.data);
         // skip the frame head
-        r.set_position(16);
+        r.set_position(self.head_len as u64);
 
         let ty = r.read_u8()?;
-        // we don't need to check len here, frame is checked already
+        // the len is from the peer, it may exceed the frame
         let len = r.read_u64::<BigEndian>()? as usize;
 
+        let start = self.head_len + 9;
         let buf = r.into_inner();
-        let data = &buf[25..len + 25];
+        let data = match start.checked_add(len).and_then(|end| buf.get(start..end)) {
+            Some(data) => data,
+            None => {
+                let s = format!("invalid response len. len={len}, frame_len={}", buf.len());
+                error!("{s}");
+                return Err(ClientDeserialize(s));
+            }
+        };
 
         // info!("decode response, ty={}, len={}", ty, len);
         match ty {
-            0 => Ok(data),
-            1 => Err(ServerDeserialize(unsafe {
-                String::from_utf8_unchecked(data.into())
-            })),
-            2 => Err(ServerSerialize(unsafe {
-                String::from_utf8_unchecked(data.into())
-            })),
-            3 => Err(Status(unsafe { String::from_utf8_unchecked(data.into()) })),
+            0 | RSP_STREAM_ITEM | RSP_STREAM_END => Ok(data),
+            1 => Err(ServerDeserialize(
+                String::from_utf8_lossy(data).into_owned(),
+            )),
+            2 => Err(ServerSerialize(String::from_utf8_lossy(data).into_owned())),
+            3 => Err(decode_status(data)?),
             _ => {
                 let s = format!("invalid response type. ty={ty}");
                 error!("{s}");
@@ -111,8 +377,96 @@ impl Frame {
     }
 }
 
+/// decode the status error, the layout is
+/// code(u32) + message_len(u32) + message([u8; message_len]) + details
+fn decode_status(data: &[u8]) -> Result<Error, Error> {
+    let invalid = || Error::ClientDeserialize("invalid status response".to_owned());
+    let code = data.get(..4).ok_or_else(invalid)?;
+    let code = StatusCode(BigEndian::read_u32(code));
+    let len = data.get(4..8).ok_or_else(invalid)?;
+    let len = BigEndian::read_u32(len) as usize;
+    let message = data.get(8..8 + len).ok_or_else(invalid)?;
+    let message = String::from_utf8_lossy(message).into_owned();
+    let details = data[8 + len..].to_vec();
+    Ok(Error::Status {
+        code,
+        message,
+        details,
+    })
+}
+
+/// encode the status error
+fn encode_status(code: StatusCode, message: &str, details: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + message.len() + details.len());
+    buf.write_u32::<BigEndian>(code.0).unwrap();
+    buf.write_u32::<BigEndian>(message.len() as u32).unwrap();
+    buf.extend_from_slice(message.as_bytes());
+    buf.extend_from_slice(details);
+    buf
+}
+
+/// append the crc32c trailer to the frame that starts at `start` and mark it in the flags
+fn seal_checksum(buf: &mut Vec<u8>, start: usize) {
+    buf[start + 8] |= FLAG_CHECKSUM;
+    let crc = crc32c::crc32c(&buf[start..]);
+    buf.write_u32::<BigEndian>(crc).unwrap();
+}
+
+/// set the flags and checksum of the encoded frame, the frame is split
+/// into continuation frames if it's longer than `max_len`
+fn seal_frame(mut buf: Vec<u8>, flags: u8, max_len: usize, checksum: bool) -> Vec<u8> {
+    let chunk_len = max_len.saturating_sub(16).max(1);
+    if buf.len() - 16 <= chunk_len {
+        buf[8] |= flags;
+        if checksum {
+            seal_checksum(&mut buf, 0);
+        }
+        return buf;
+    }
+
+    let (head, payload) = buf.split_at(16);
+    let n = payload.len().div_ceil(chunk_len);
+    let mut out = Vec::with_capacity(payload.len() + n * 20);
+    let mut chunks = payload.chunks(chunk_len).peekable();
+    while let Some(chunk) = chunks.next() {
+        let start = out.len();
+        let mut flags = flags;
+        if chunks.peek().is_some() {
+            flags |= FLAG_MORE;
+        }
+        out.extend_from_slice(&head[..8]);
+        out.write_u64::<BigEndian>(chunk.len() as u64 | (u64::from(flags) << 56))
+            .unwrap();
+        out.extend_from_slice(chunk);
+        if checksum {
+            seal_checksum(&mut out, start);
+        }
+    }
+    info!("encode {n} continuation frames");
+    out
+}
+
+/// encode a control frame
+pub(crate) fn encode_control(id: u64, ctrl: Control) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(17);
+    buf.write_u64::<BigEndian>(id).unwrap();
+    buf.write_u64::<BigEndian>(1 | (u64::from(FLAG_CONTROL) << 56))
+        .unwrap();
+    buf.write_u8(ctrl as u8).unwrap();
+    buf
+}
+
 /// req frame buffer that can be serialized into
-pub struct ReqBuf(Cursor<Vec<u8>>);
+#[derive(Clone)]
+pub struct ReqBuf {
+    buf: Cursor<Vec<u8>>,
+    checksum: bool,
+    flags: u8,
+    timeout: Option<Duration>,
+    metadata: Metadata,
+    max_len: usize,
+    max_msg_len: usize,
+}
 
 impl Default for ReqBuf {
     fn default() -> Self {
@@ -127,14 +481,104 @@ impl ReqBuf {
         let mut cursor = Cursor::new(buf);
         // leave enough space to write id and len
         cursor.set_position(16);
-        ReqBuf(cursor)
+        ReqBuf {
+            buf: cursor,
+            checksum: false,
+            flags: 0,
+            timeout: None,
+            metadata: Metadata::new(),
+            max_len: FRAME_MAX_LEN,
+            max_msg_len: MSG_MAX_LEN,
+        }
     }
 
-    /// convert self into raw buf that can be send as a frame
-    pub fn finish(self, id: u64) -> Vec<u8> {
-        let mut cursor = self.0;
-        let len = cursor.get_ref().len() as u64;
-        assert!(len <= FRAME_MAX_LEN);
+    /// append a crc32c checksum to the encoded frame
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    /// mark the req as expecting a streaming rsp
+    pub(crate) fn set_stream(&mut self) {
+        self.flags |= FLAG_STREAM;
+    }
+
+    /// mark the req as an item or the end of a streaming req
+    pub(crate) fn set_duplex(&mut self, end: bool) {
+        self.flags |= FLAG_STREAM | FLAG_DUPLEX;
+        if end {
+            self.flags |= FLAG_END;
+        }
+    }
+
+    /// send the timeout of the client along with the req
+    /// the server would see it as the deadline of the req
+    /// if not set, the client would send its own timeout.
+    /// `MultiplexClient` also waits the rsp with this timeout
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// the timeout that is sent along with the req
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// the metadata that is sent along with the req
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+
+    /// set the method name that the `Router` routes the req by
+    pub fn set_method(&mut self, name: &str) {
+        self.metadata.insert(METHOD_KEY, name);
+    }
+
+    /// set the method id that the `Router` routes the req by
+    pub fn set_method_id(&mut self, id: u64) {
+        self.metadata.insert(METHOD_ID_KEY, id.to_be_bytes());
+    }
+
+    /// set the max frame len, a longer req is split into continuation frames
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    /// set the max message len that the encoded req can't exceed
+    pub fn set_max_msg_len(&mut self, max_msg_len: usize) {
+        self.max_msg_len = max_msg_len;
+    }
+
+    /// convert self into raw buf that can be send as one or more frames
+    /// return `Error::MessageTooLarge` if the req exceeds the max message len
+    pub fn finish(self, id: u64) -> Result<Vec<u8>, Error> {
+        let mut cursor = self.buf;
+        let mut flags = self.flags;
+        // insert the head extensions right after the head
+        let mut ext = Vec::new();
+        if let Some(timeout) = self.timeout {
+            let timeout = u64::try_from(timeout.as_micros()).unwrap_or(u64::MAX);
+            ext.write_u64::<BigEndian>(timeout).unwrap();
+            flags |= FLAG_DEADLINE;
+        }
+        if !self.metadata.is_empty() {
+            self.metadata.encode(&mut ext);
+            flags |= FLAG_HEADERS;
+        }
+        if !ext.is_empty() {
+            cursor.get_mut().splice(16..16, ext);
+        }
+        let len = cursor.get_ref().len();
+        if len > self.max_msg_len {
+            error!(
+                "encode too big req message length. len={len}, max={}",
+                self.max_msg_len
+            );
+            return Err(Error::MessageTooLarge {
+                len,
+                max: self.max_msg_len,
+            });
+        }
+        let len = len as u64;
 
         // write from start
         cursor.set_position(0);
@@ -145,13 +589,14 @@ impl ReqBuf {
         cursor.write_u64::<BigEndian>(len - 16).unwrap();
         info!("encode len = {:?}", len);
 
-        cursor.into_inner()
+        let buf = cursor.into_inner();
+        Ok(seal_frame(buf, flags, self.max_len, self.checksum))
     }
 }
 
 impl Write for ReqBuf {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.buf.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -160,7 +605,13 @@ impl Write for ReqBuf {
 }
 
 /// rsp frame buffer that can be serialized into
-pub struct RspBuf(Cursor<Vec<u8>>);
+pub struct RspBuf {
+    buf: Cursor<Vec<u8>>,
+    checksum: bool,
+    metadata: Metadata,
+    max_len: usize,
+    max_msg_len: usize,
+}
 
 impl Default for RspBuf {
     fn default() -> Self {
@@ -177,26 +628,82 @@ impl RspBuf {
         let mut cursor = Cursor::new(buf);
         // leave enough space to write id and len
         cursor.set_position(25);
-        RspBuf(cursor)
+        RspBuf {
+            buf: cursor,
+            checksum: false,
+            metadata: Metadata::new(),
+            max_len: FRAME_MAX_LEN,
+            max_msg_len: MSG_MAX_LEN,
+        }
+    }
+
+    /// the metadata that is sent along with the rsp
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
     }
 
-    /// convert self into raw buf that can be send as a frame
+    /// append a crc32c checksum to the encoded frame
+    /// the server would set this when the request carried a checksum
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    /// set the max frame len, a longer rsp is split into continuation frames
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    /// set the max message len that the encoded rsp can't exceed
+    pub fn set_max_msg_len(&mut self, max_msg_len: usize) {
+        self.max_msg_len = max_msg_len;
+    }
+
+    /// return the encoded frame len so far
+    pub(crate) fn frame_len(&self) -> usize {
+        self.buf.get_ref().len()
+    }
+
+    /// convert self into raw buf that can be send as one or more frames
+    /// a response that exceeds the max message len is replaced by a `ServerSerialize` error
     pub fn finish(self, id: u64, ret: Result<(), WireError>) -> Vec<u8> {
-        let mut cursor = self.0;
-        let dummy = Vec::new();
+        self.finish_as(id, 0, ret)
+    }
+
+    /// convert self into raw buf with the given rsp type for the normal ret
+    pub(crate) fn finish_as(self, id: u64, ok_ty: u8, mut ret: Result<(), WireError>) -> Vec<u8> {
+        let mut cursor = self.buf;
+        let mut status = Vec::new();
+
+        if ret.is_ok() {
+            let len = cursor.get_ref().len();
+            if len > self.max_msg_len {
+                let s = format!(
+                    "encode too big rsp message length. len={len}, max={}",
+                    self.max_msg_len
+                );
+                error!("{s}");
+                ret = Err(WireError::ServerSerialize(s));
+            }
+        }
 
         let (ty, len, data) = match ret {
-            Ok(_) => (0, cursor.get_ref().len() - 25, dummy.as_slice()),
+            Ok(_) => (ok_ty, cursor.get_ref().len() - 25, status.as_slice()),
             Err(ref e) => match *e {
                 WireError::ServerDeserialize(ref s) => (1, s.len(), s.as_bytes()),
                 WireError::ServerSerialize(ref s) => (2, s.len(), s.as_bytes()),
-                WireError::Status(ref s) => (3, s.len(), s.as_bytes()),
-                WireError::Polling => (SERVER_POLL_ENCODE, 0, dummy.as_slice()),
+                WireError::Status {
+                    code,
+                    ref message,
+                    ref details,
+                } => {
+                    status = encode_status(code, message, details);
+                    (3, status.len(), status.as_slice())
+                }
+                WireError::Polling => (SERVER_POLL_ENCODE, 0, status.as_slice()),
             },
         };
 
         let len = len as u64;
-        assert!(len < FRAME_MAX_LEN);
 
         // write from start
         cursor.set_position(0);
@@ -213,7 +720,7 @@ impl RspBuf {
         cursor.write_u64::<BigEndian>(len).unwrap();
         // write the data into the writer
         match ty {
-            0 => {} // the normal ret already wrote
+            0 | RSP_STREAM_ITEM | RSP_STREAM_END => {} // the normal ret already wrote
             SERVER_POLL_ENCODE => {
                 // the server need to poll the client, will be filtered out by multiplex_client
             }
@@ -224,13 +731,24 @@ impl RspBuf {
             _ => unreachable!("unknown rsp type"),
         }
 
-        cursor.into_inner()
+        let mut buf = cursor.into_inner();
+        let mut flags = 0;
+        if !self.metadata.is_empty() {
+            // insert the metadata right after the head
+            let mut ext = Vec::new();
+            self.metadata.encode(&mut ext);
+            buf.splice(16..16, ext);
+            let len = (buf.len() - 16) as u64;
+            buf[8..16].copy_from_slice(&len.to_be_bytes());
+            flags |= FLAG_HEADERS;
+        }
+        seal_frame(buf, flags, self.max_len, self.checksum)
     }
 }
 
 impl Write for RspBuf {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.buf.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -0,0 +1,150 @@
+use std::io::{Read, Write};
+use std::ops::BitOr;
+
+use crate::errors::Error;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+// connection preface layout
+// magic([u8; 4]) + version(u8) + features(u32)
+
+// the client sends its preface once connected, and the server replies with
+// its own preface that carries the negotiated features. the server would
+// close the connection after replying if the version doesn't match
+
+// a req frame that uses a feature not negotiated is rejected by the server
+// with an `INVALID_ARGUMENT` status, and the control frames of it are ignored
+
+/// magic bytes of the connection preface
+pub const PREFACE_MAGIC: [u8; 4] = *b"CNTY";
+/// the wire protocol version
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// optional protocol features negotiated by the connection preface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Features(u32);
+
+impl Features {
+    /// crc32c checksum of the frames
+    pub const CHECKSUM: Features = Features(1);
+    /// continuation frames of large messages
+    pub const CHUNKING: Features = Features(1 << 1);
+    /// deadline of the requests
+    pub const DEADLINE: Features = Features(1 << 2);
+    /// metadata headers of the frames
+    pub const METADATA: Features = Features(1 << 3);
+    /// streaming requests and responses
+    pub const STREAMING: Features = Features(1 << 4);
+    /// cancellation of the requests
+    pub const CANCEL: Features = Features(1 << 5);
+    /// ping and pong heartbeat of the connection
+    pub const HEARTBEAT: Features = Features(1 << 6);
+
+    /// no feature
+    pub const fn empty() -> Self {
+        Features(0)
+    }
+
+    /// all the features supported by this version
+    pub const fn all() -> Self {
+        Features((1 << 7) - 1)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// create from the raw bits, unknown bits are kept
+    pub const fn from_bits(bits: u32) -> Self {
+        Features(bits)
+    }
+
+    /// return true if all the features of `other` are contained
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// the features that are supported by both sides
+    pub const fn intersection(self, other: Features) -> Self {
+        Features(self.0 & other.0)
+    }
+
+    /// the features without the ones of `other`
+    pub const fn difference(self, other: Features) -> Self {
+        Features(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/// write the connection preface
+pub(crate) fn write_preface<W: Write>(w: &mut W, features: Features) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(9);
+    buf.extend_from_slice(&PREFACE_MAGIC);
+    buf.write_u8(PROTOCOL_VERSION).unwrap();
+    buf.write_u32::<BigEndian>(features.bits()).unwrap();
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+/// read the connection preface, return the version and features of the peer
+pub(crate) fn read_preface<R: Read>(r: &mut R) -> Result<(u8, Features), Error> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if magic != PREFACE_MAGIC {
+        error!("invalid preface magic: {:?}", magic);
+        return Err(Error::Handshake(format!("invalid preface magic {magic:?}")));
+    }
+    let version = r.read_u8()?;
+    let features = Features::from_bits(r.read_u32::<BigEndian>()?);
+    Ok((version, features))
+}
+
+/// read the preface replied by the server, return the negotiated features
+pub(crate) fn read_server_preface<R: Read>(r: &mut R) -> Result<Features, Error> {
+    let (version, features) = read_preface(r)?;
+    if version != PROTOCOL_VERSION {
+        error!("protocol version mismatch, local={PROTOCOL_VERSION}, remote={version}");
+        return Err(Error::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: version,
+        });
+    }
+    info!("client handshake done, features={:?}", features);
+    Ok(features)
+}
+
+/// the client side of the handshake, offer the features and return the negotiated ones
+pub(crate) fn client_handshake<S: Read + Write>(
+    s: &mut S,
+    features: Features,
+) -> Result<Features, Error> {
+    write_preface(s, features)?;
+    read_server_preface(s)
+}
+
+/// the server side of the handshake, return the features supported by both sides
+pub(crate) fn server_handshake<R: Read, W: Write>(
+    r: &mut R,
+    w: &mut W,
+    supported: Features,
+) -> Result<Features, Error> {
+    let (version, features) = read_preface(r)?;
+    let features = features.intersection(supported.intersection(Features::all()));
+    // reply the preface anyway so that the client can report the mismatch
+    write_preface(w, features)?;
+    if version != PROTOCOL_VERSION {
+        error!("protocol version mismatch, local={PROTOCOL_VERSION}, remote={version}");
+        return Err(Error::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: version,
+        });
+    }
+    info!("server handshake done, features={:?}", features);
+    Ok(features)
+}
@@ -0,0 +1,105 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::frame::{encode_control, Control};
+use crate::queued_writer::FrameWriter;
+
+use may::{coroutine, go};
+
+// the heartbeat is a ping control frame sent on each interval, the peer
+// replies a pong control frame with the same id. any frame received from
+// the peer proves it's alive, and the peer is considered dead once nothing
+// is received within the timeout. the pings are only sent to the peer
+// that negotiated the HEARTBEAT feature
+
+// marks the liveness as paused
+const PAUSED: u64 = u64::MAX;
+
+/// the last time a frame is received from the peer
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    start: Instant,
+    // millis since start
+    last_seen: AtomicU64,
+}
+
+impl Liveness {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Liveness {
+            start: Instant::now(),
+            last_seen: AtomicU64::new(0),
+        })
+    }
+
+    /// record that the peer is alive now
+    pub fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_seen.store(now, Ordering::Relaxed);
+    }
+
+    /// stop checking the peer until the next `touch`,
+    /// used when we stop reading the peer on purpose
+    pub fn pause(&self) {
+        self.last_seen.store(PAUSED, Ordering::Relaxed);
+    }
+
+    /// how long nothing is received from the peer
+    fn idle(&self) -> Duration {
+        match self.last_seen.load(Ordering::Relaxed) {
+            PAUSED => Duration::ZERO,
+            last => self
+                .start
+                .elapsed()
+                .saturating_sub(Duration::from_millis(last)),
+        }
+    }
+}
+
+/// the running heartbeat coroutine, which is cancelled when dropped
+#[derive(Debug)]
+pub(crate) struct Heartbeat(coroutine::JoinHandle<()>);
+
+impl Heartbeat {
+    /// send a ping on each interval, and call `on_dead` once
+    /// if nothing is received from the peer within the timeout
+    pub fn spawn<F>(
+        interval: Duration,
+        timeout: Duration,
+        liveness: Arc<Liveness>,
+        writer: FrameWriter,
+        on_dead: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        liveness.touch();
+        let h = go!(
+            coroutine::Builder::new().name("Heartbeat".to_owned()),
+            move || {
+                let mut seq = 0u64;
+                loop {
+                    coroutine::sleep(interval);
+                    let idle = liveness.idle();
+                    if idle > timeout {
+                        warn!("heartbeat timeout, the peer is silent for {:?}", idle);
+                        on_dead();
+                        break;
+                    }
+                    writer(encode_control(seq, Control::Ping));
+                    seq = seq.wrapping_add(1);
+                }
+            }
+        )?;
+        Ok(Heartbeat(h))
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if !self.0.is_done() {
+            unsafe { self.0.coroutine().cancel() };
+        }
+    }
+}
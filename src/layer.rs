@@ -0,0 +1,104 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::context::RequestContext;
+use crate::errors::WireError;
+use crate::frame::RspBuf;
+
+type Handler<'a> = &'a dyn Fn(&RequestContext, &[u8], &mut RspBuf) -> Result<(), WireError>;
+
+/// server middleware that wraps the service of the requests
+///
+/// a layer sees the request and its context before calling `next.run`, and the
+/// `RspBuf` and the result after. it can also reply on its own without calling
+/// `next`, like rejecting an unauthorized request. the layers are applied by
+/// `ServerBuilder::layer`, and the first added layer is the outermost one
+///
+/// the streaming requests pass the layers too, where `next.run` runs the whole
+/// streaming service and the items are sent by the service instead of the
+/// `RspBuf`, so a layer can still check the context and reject the stream.
+/// a duplex stream passes the layers with the context of its opening frame
+/// and an empty req
+///
+/// closures with the same signature as `call` are also layers
+pub trait Layer: Send + Sync + 'static {
+    /// handle the request, call `next.run` to pass it to the inner layers and the service
+    fn call(
+        &self,
+        ctx: &RequestContext,
+        req: &[u8],
+        rsp: &mut RspBuf,
+        next: Next<'_>,
+    ) -> Result<(), WireError>;
+}
+
+impl<F> Layer for F
+where
+    F: Fn(&RequestContext, &[u8], &mut RspBuf, Next<'_>) -> Result<(), WireError>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn call(
+        &self,
+        ctx: &RequestContext,
+        req: &[u8],
+        rsp: &mut RspBuf,
+        next: Next<'_>,
+    ) -> Result<(), WireError> {
+        self(ctx, req, rsp, next)
+    }
+}
+
+/// the rest of the layer chain, ended by the service
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Layer>],
+    handler: Handler<'a>,
+}
+
+impl Next<'_> {
+    /// run the inner layers and the service
+    pub fn run(self, ctx: &RequestContext, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => {
+                let next = Next {
+                    layers,
+                    handler: self.handler,
+                };
+                layer.call(ctx, req, rsp, next)
+            }
+            None => (self.handler)(ctx, req, rsp),
+        }
+    }
+}
+
+/// the layers of the server, in the order of adding
+#[derive(Clone, Default)]
+pub(crate) struct Layers(Vec<Arc<dyn Layer>>);
+
+impl Layers {
+    pub fn push<L: Layer>(&mut self, layer: L) {
+        self.0.push(Arc::new(layer));
+    }
+
+    /// run the request through the layers and the handler
+    pub fn run(
+        &self,
+        ctx: &RequestContext,
+        req: &[u8],
+        rsp: &mut RspBuf,
+        handler: Handler<'_>,
+    ) -> Result<(), WireError> {
+        let next = Next {
+            layers: &self.0,
+            handler,
+        };
+        next.run(ctx, req, rsp)
+    }
+}
+
+impl fmt::Debug for Layers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Layers({})", self.0.len())
+    }
+}
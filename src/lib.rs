@@ -1,14 +1,32 @@
 #[macro_use]
 extern crate log;
 
-pub use errors::{Error, WireError};
-pub use frame::{Frame, ReqBuf, RspBuf};
-pub use multiplex_client::MultiplexClient;
-pub use server::{ServerInstance, TcpServer, UdpServer};
+pub use builder::{ConnectionPolicy, InflightPolicy, ServerBuilder};
+pub use client_layer::{
+    ClientLayer, ClientMetrics, DefaultHeaders, DefaultTimeout, Layered, Logging, Metrics, Retry,
+};
+#[cfg(feature = "bincode")]
+pub use codec::Bincode;
+#[cfg(feature = "json")]
+pub use codec::Json;
+pub use codec::{ClientExt, Codec, CodecError, Raw, TypedServer};
+pub use context::{Peer, RequestContext};
+pub use errors::{Error, StatusCode, WireError};
+pub use frame::{Frame, ReqBuf, RspBuf, FRAME_MAX_LEN, MSG_MAX_LEN};
+pub use handshake::{Features, PREFACE_MAGIC, PROTOCOL_VERSION};
+pub use layer::{Layer, Next};
+pub use metadata::{Metadata, METADATA_VERSION};
+pub use multiplex_client::{MultiplexClient, PendingCall};
+pub use reconnecting_client::{ConnState, ReconnectingClient};
+pub use router::{method_id, Router, METHOD_ID_KEY, METHOD_KEY};
+pub use server::{ServerInstance, ServerMetrics, ShutdownReport, TcpServer, UdpServer};
+pub use stream::{ReqReceiver, ReqSender, RspReceiver, RspSender};
 pub use stream_client::StreamClient;
 pub use stream_ext::StreamExt;
 pub use udp_client::UdpClient;
 
+#[cfg(unix)]
+pub use context::PeerCred;
 #[cfg(unix)]
 pub use server::UdsServer;
 
@@ -41,16 +59,103 @@ pub trait Server: Send + Sync + Sized + 'static {
     /// application error should be encapsulated into the RspBuf
     /// here passed in a self ref to impl stateful service if you want
     fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError>;
+
+    /// the service with the request context
+    /// impl this instead of `service` if you need the context of the request
+    /// the default impl forwards to `service`
+    fn service_with_context(
+        &self,
+        _ctx: &RequestContext,
+        req: &[u8],
+        rsp: &mut RspBuf,
+    ) -> Result<(), WireError> {
+        self.service(req, rsp)
+    }
+
+    /// the streaming service that would run in a coroutine
+    /// this is called for the request that expects a streaming response
+    /// each response item should be serialized into a RspBuf and sent by the RspSender
+    /// the stream is ended when it returns, an Err would be sent as the last response
+    /// the default impl sends the response of `service` as the only item
+    fn service_stream(&self, req: &[u8], rsp: &mut RspSender) -> Result<(), WireError> {
+        let mut buf = RspBuf::new();
+        self.service(req, &mut buf)?;
+        rsp.send(buf)
+    }
+
+    /// the streaming service with the request context
+    /// impl this instead of `service_stream` if you need the context of the request
+    /// the default impl forwards to `service_stream`
+    fn service_stream_with_context(
+        &self,
+        _ctx: &RequestContext,
+        req: &[u8],
+        rsp: &mut RspSender,
+    ) -> Result<(), WireError> {
+        self.service_stream(req, rsp)
+    }
+
+    /// the bidirectional streaming service that would run in a coroutine
+    /// this is called for the streaming request opened by `MultiplexClient::open_stream`
+    /// the request items are received from the ReqReceiver until the client half-closes,
+    /// the response items are sent by the RspSender at any time
+    /// the stream is ended when it returns, an Err would be sent as the last response
+    /// the default impl calls `service_stream` for each request item
+    fn service_duplex(&self, reqs: &mut ReqReceiver, rsp: &mut RspSender) -> Result<(), WireError> {
+        for req in reqs {
+            self.service_stream(req.decode_req(), rsp)?;
+        }
+        Ok(())
+    }
+
+    /// the bidirectional streaming service with the request context
+    /// the context is made from the frame that opens the stream
+    /// impl this instead of `service_duplex` if you need the context of the request
+    /// the default impl forwards to `service_duplex`
+    fn service_duplex_with_context(
+        &self,
+        _ctx: &RequestContext,
+        reqs: &mut ReqReceiver,
+        rsp: &mut RspSender,
+    ) -> Result<(), WireError> {
+        self.service_duplex(reqs, rsp)
+    }
 }
 
+/// Provides server builder
+mod builder;
+/// Provides client middleware
+mod client_layer;
+/// Provides serialization codecs for typed calls
+mod codec;
+/// Provides request context
+mod context;
 /// Provides a few different error types
 mod errors;
 /// raw frame protocol
 mod frame;
+/// Provides connection preface
+mod handshake;
+/// Provides connection heartbeat
+mod heartbeat;
+/// Provides server middleware
+mod layer;
+/// Provides metadata headers
+mod metadata;
 mod multiplex_client;
 mod queued_writer;
+/// Provides auto reconnecting client
+mod reconnecting_client;
+/// Provides method routing
+mod router;
+/// Provides typed rpc service definition
+#[cfg(feature = "bincode")]
+pub mod rpc;
+mod semaphore;
 /// Provides server framework
 mod server;
+/// Provides streaming request and response
+mod stream;
 
 /// Provide stream client
 mod stream_client;
@@ -0,0 +1,132 @@
+use std::io;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+// metadata section layout
+// version(u8) + len(u32) + entries([u8; len])
+// entry: key_len(u16) + key([u8; key_len]) + value_len(u32) + value([u8; value_len])
+
+// the metadata section is placed right after the frame head when the headers flag is set.
+// an old peer that doesn't know the flag would see a huge frame len and close the
+// connection, and a newer section version is rejected instead of misparsed
+
+/// version of the metadata section layout
+pub const METADATA_VERSION: u8 = 1;
+
+/// key/value metadata carried by the request and response frames
+///
+/// the keys are unique, inserting an existing key replaces its value
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Metadata::default()
+    }
+
+    /// insert a key/value pair, return the old value of the key if any
+    ///
+    /// panics if the key is longer than `u16::MAX` or the value is longer than `u32::MAX`
+    pub fn insert<K: Into<String>, V: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Option<Vec<u8>> {
+        let key = key.into();
+        let value = value.into();
+        assert!(key.len() <= u16::MAX as usize, "metadata key too long");
+        assert!(value.len() <= u32::MAX as usize, "metadata value too long");
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => Some(std::mem::replace(v, value)),
+            None => {
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// get the value of the key
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// remove the key, return its value if any
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        let i = self.entries.iter().position(|(k, _)| k == key)?;
+        Some(self.entries.remove(i).1)
+    }
+
+    /// iterate the key/value pairs in the insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// encode the metadata section into the buf
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let len: usize = self
+            .entries
+            .iter()
+            .map(|(k, v)| 6 + k.len() + v.len())
+            .sum();
+        buf.write_u8(METADATA_VERSION).unwrap();
+        buf.write_u32::<BigEndian>(len as u32).unwrap();
+        for (k, v) in self.entries.iter() {
+            buf.write_u16::<BigEndian>(k.len() as u16).unwrap();
+            buf.extend_from_slice(k.as_bytes());
+            buf.write_u32::<BigEndian>(v.len() as u32).unwrap();
+            buf.extend_from_slice(v);
+        }
+    }
+
+    /// decode the metadata section from the buf, return the metadata and the section len
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<(Self, usize)> {
+        let invalid = |s: &str| io::Error::new(io::ErrorKind::InvalidData, s.to_owned());
+
+        let version = *buf.first().ok_or_else(|| invalid("invalid metadata"))?;
+        if version != METADATA_VERSION {
+            error!("unsupported metadata version. version={version}");
+            return Err(invalid("unsupported metadata version"));
+        }
+        let len = buf.get(1..5).ok_or_else(|| invalid("invalid metadata"))?;
+        let len = BigEndian::read_u32(len) as usize;
+        let mut r = buf
+            .get(5..5 + len)
+            .ok_or_else(|| invalid("invalid metadata"))?;
+
+        let mut md = Metadata::new();
+        while !r.is_empty() {
+            let key_len = r.get(..2).ok_or_else(|| invalid("invalid metadata key"))?;
+            let key_len = BigEndian::read_u16(key_len) as usize;
+            let key = r
+                .get(2..2 + key_len)
+                .ok_or_else(|| invalid("invalid metadata key"))?;
+            let key =
+                String::from_utf8(key.to_vec()).map_err(|_| invalid("invalid metadata key"))?;
+            r = &r[2 + key_len..];
+
+            let value_len = r
+                .get(..4)
+                .ok_or_else(|| invalid("invalid metadata value"))?;
+            let value_len = BigEndian::read_u32(value_len) as usize;
+            let value = r
+                .get(4..4 + value_len)
+                .ok_or_else(|| invalid("invalid metadata value"))?;
+            md.entries.push((key, value.to_vec()));
+            r = &r[4 + value_len..];
+        }
+        Ok((md, 5 + len))
+    }
+}
@@ -1,22 +1,99 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::io::{self, BufReader};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::errors::Error;
-use crate::frame::{Frame, ReqBuf};
-use crate::queued_writer::QueuedWriter;
+use crate::errors::{Error, StatusCode};
+use crate::frame::{encode_control, Control, Frame, ReqBuf, FRAME_MAX_LEN, MSG_MAX_LEN};
+use crate::handshake::{client_handshake, Features};
+use crate::heartbeat::{Heartbeat, Liveness};
+use crate::queued_writer::{FrameWriter, QueuedWriter};
+use crate::stream::{ReqSender, RspReceiver, StreamMap, STREAM_ID_BIT};
 use crate::stream_ext::StreamExt;
 use crate::Client;
 
 use may::io::SplitWriter;
+use may::sync::{mpsc, Mutex};
 use may::{coroutine, go};
 use may_waiter::TokenWaiter;
 
+// the waiter would get either the rsp frame or the error that fails the request
+type RspWaiter = TokenWaiter<Result<Frame, Error>>;
+
+/// deliver the rsp to the waiting request or the stream
+/// return false if nobody is waiting for the id
+fn dispatch_rsp(
+    pending: &Mutex<HashSet<u64>>,
+    streams: &StreamMap,
+    id: u64,
+    rsp: Result<Frame, Error>,
+) -> bool {
+    if id & STREAM_ID_BIT != 0 {
+        return match streams.lock().unwrap().get(&id) {
+            Some(tx) => {
+                tx.send(rsp).ok();
+                true
+            }
+            None => false,
+        };
+    }
+
+    // the waiter is alive while its id is pending, hold the lock to keep it
+    let pending = pending.lock().unwrap();
+    if !pending.contains(&id) {
+        return false;
+    }
+    let id = unsafe { may_waiter::ID::from_usize(id as usize) };
+    RspWaiter::set_rsp(id, rsp);
+    true
+}
+
+/// mark the connection as closed, and fail all the waiting calls and streams with the error
+fn close_conn<F>(closed: &AtomicBool, pending: &Mutex<HashSet<u64>>, streams: &StreamMap, err: F)
+where
+    F: Fn() -> Error,
+{
+    closed.store(true, Ordering::Release);
+    for id in pending.lock().unwrap().drain() {
+        let id = unsafe { may_waiter::ID::from_usize(id as usize) };
+        RspWaiter::set_rsp(id, Err(err()));
+    }
+    for (_, tx) in streams.lock().unwrap().drain() {
+        tx.send(Err(err())).ok();
+    }
+}
+
 pub struct MultiplexClient<S: StreamExt> {
-    // default timeout is 10s
+    // no timeout by default
     timeout: Option<Duration>,
+    // append checksum to the request frames
+    checksum: bool,
+    // max frame len of both request and response
+    max_frame_len: usize,
+    // max len of the message that is split into continuation frames
+    max_msg_len: usize,
+    // the features negotiated with the server
+    features: Features,
+    // set when the server is going away
+    going_away: Arc<AtomicBool>,
+    // set when the connection is dead, the new calls would fail fast
+    closed: Arc<AtomicBool>,
+    // the ids of the calls waiting for the rsp
+    pending: Arc<Mutex<HashSet<u64>>>,
+    // the running streaming requests
+    streams: Arc<StreamMap>,
+    // id generator for the streaming requests
+    stream_id: AtomicU64,
     // the connection
-    sock: QueuedWriter<SplitWriter<S>>,
+    sock: Arc<QueuedWriter<SplitWriter<S>>>,
+    // used to close the connection when the heartbeat timeout
+    ctl: Arc<Mutex<S>>,
+    // the last time a frame is received, updated by the listener
+    liveness: Arc<Liveness>,
+    // the running heartbeat
+    heartbeat: Option<Heartbeat>,
     // the listening coroutine
     listener: Option<coroutine::JoinHandle<()>>,
 }
@@ -25,6 +102,9 @@ impl<S: StreamExt> fmt::Debug for MultiplexClient<S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MultiplexClient")
             .field("timeout", &self.timeout)
+            .field("checksum", &self.checksum)
+            .field("max_frame_len", &self.max_frame_len)
+            .field("max_msg_len", &self.max_msg_len)
             .field("listener", &self.listener)
             .finish()
     }
@@ -42,62 +122,450 @@ impl<S: StreamExt> Drop for MultiplexClient<S> {
 
 impl<S: StreamExt> MultiplexClient<S> {
     /// connect to the server address
-    pub fn new(stream: S) -> io::Result<Self> {
+    /// return `Error::VersionMismatch` if the server speaks a different protocol version
+    pub fn new(stream: S) -> Result<Self, Error> {
+        Self::with_max_frame_len(stream, FRAME_MAX_LEN)
+    }
+
+    /// connect to the server address with the max frame len
+    /// the connection would be closed if receive a frame longer than `max_frame_len`
+    pub fn with_max_frame_len(stream: S, max_frame_len: usize) -> Result<Self, Error> {
+        Self::with_limits(stream, max_frame_len, MSG_MAX_LEN.max(max_frame_len))
+    }
+
+    /// connect to the server address with the max frame len and max message len
+    /// longer requests are split into continuation frames of `max_frame_len`,
+    /// the connection would be closed if receive a message longer than `max_msg_len`
+    pub fn with_limits(stream: S, max_frame_len: usize, max_msg_len: usize) -> Result<Self, Error> {
+        Self::connect(stream, max_frame_len, max_msg_len, None, Features::all())
+    }
+
+    /// connect to the server address, the handshake must be done within the timeout
+    /// return `Error::Io` with a timed out error if the server doesn't reply the preface
+    pub fn with_handshake_timeout(stream: S, timeout: Duration) -> Result<Self, Error> {
+        let max_msg_len = MSG_MAX_LEN.max(FRAME_MAX_LEN);
+        Self::connect(
+            stream,
+            FRAME_MAX_LEN,
+            max_msg_len,
+            Some(timeout),
+            Features::all(),
+        )
+    }
+
+    /// connect to the server address and offer only the features
+    /// the parts of the requests that are not negotiated are left out,
+    /// like the checksum and the deadline
+    pub fn with_features(stream: S, features: Features) -> Result<Self, Error> {
+        let max_msg_len = MSG_MAX_LEN.max(FRAME_MAX_LEN);
+        Self::connect(stream, FRAME_MAX_LEN, max_msg_len, None, features)
+    }
+
+    pub(crate) fn connect(
+        mut stream: S,
+        max_frame_len: usize,
+        max_msg_len: usize,
+        handshake_timeout: Option<Duration>,
+        features: Features,
+    ) -> Result<Self, Error> {
+        if handshake_timeout.is_some() {
+            stream.set_read_timeout(handshake_timeout)?;
+        }
+        let features = client_handshake(&mut stream, features)?;
+        // the large messages can't be split without the continuation frames
+        let max_msg_len = match features.contains(Features::CHUNKING) {
+            true => max_msg_len,
+            false => max_frame_len,
+        };
+        // cleared before the listener starts reading
+        if handshake_timeout.is_some() {
+            stream.set_read_timeout(None)?;
+        }
+        let ctl = Arc::new(Mutex::new(stream.try_clone()?));
         // here we must clone the socket for read
         // we can't share it between coroutines
         let (reader, writer) = stream.split()?;
         let mut r_stream = BufReader::new(reader);
+        let sock = Arc::new(QueuedWriter::new(writer));
+        let pong_sock = sock.clone();
+        let liveness = Liveness::new();
+        let rsp_liveness = liveness.clone();
+        let going_away = Arc::new(AtomicBool::new(false));
+        let server_going_away = going_away.clone();
+        let streams: Arc<StreamMap> = Arc::new(Mutex::new(HashMap::new()));
+        let rsp_streams = streams.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        let rsp_closed = closed.clone();
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let rsp_pending = pending.clone();
         let listener = go!(
             coroutine::Builder::new().name("MultiPlexClientListener".to_owned()),
             move || {
                 loop {
-                    let rsp_frame = match Frame::decode_from(&mut r_stream) {
+                    let rsp_frame = match Frame::decode_from_with_limits(
+                        &mut r_stream,
+                        max_frame_len,
+                        max_msg_len,
+                    ) {
                         Ok(r) => r,
-                        Err(ref e) => {
-                            if e.kind() == io::ErrorKind::UnexpectedEof {
-                                info!("tcp multiplex_client decode rsp: connection closed");
-                            } else {
-                                error!("tcp multiplex_client decode rsp: err = {:?}", e);
+                        Err(Error::Checksum {
+                            id,
+                            expected,
+                            actual,
+                        }) => {
+                            // the frame is consumed, fail the waiting request
+                            // the id may be corrupted too, only a known one is failed
+                            let e = Error::Checksum {
+                                id,
+                                expected,
+                                actual,
+                            };
+                            if !dispatch_rsp(&rsp_pending, &rsp_streams, id, Err(e)) {
+                                warn!("tcp multiplex_client: drop corrupted rsp, id={}", id);
                             }
+                            continue;
+                        }
+                        Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                            info!("tcp multiplex_client decode rsp: connection closed");
+                            break;
+                        }
+                        Err(ref e) => {
+                            error!("tcp multiplex_client decode rsp: err = {:?}", e);
                             break;
                         }
                     };
+                    rsp_liveness.touch();
+                    if rsp_frame.is_control() {
+                        match rsp_frame.control() {
+                            Some(Control::GoAway) => {
+                                info!("tcp multiplex_client: server is going away");
+                                server_going_away.store(true, Ordering::Release);
+                            }
+                            Some(Control::Ping) => {
+                                pong_sock.write(encode_control(rsp_frame.id, Control::Pong))
+                            }
+                            Some(Control::Pong) => {}
+                            _ => warn!("tcp multiplex_client: unknown control frame"),
+                        }
+                        continue;
+                    }
                     info!("receive rsp, id={}", rsp_frame.id);
 
                     // set the wait req
-                    let id = unsafe { may_waiter::ID::from_usize(rsp_frame.id as usize) };
-                    TokenWaiter::set_rsp(id, rsp_frame);
+                    let id = rsp_frame.id;
+                    if !dispatch_rsp(&rsp_pending, &rsp_streams, id, Ok(rsp_frame)) {
+                        warn!("tcp multiplex_client: drop rsp of unknown id={}", id);
+                    }
                 }
+
+                // no more rsp, fail all the waiting calls and streams
+                close_conn(&rsp_closed, &rsp_pending, &rsp_streams, conn_closed);
             }
         )?;
 
         Ok(MultiplexClient {
             timeout: None,
-            sock: QueuedWriter::new(writer),
+            checksum: false,
+            max_frame_len,
+            max_msg_len,
+            features,
+            going_away,
+            closed,
+            pending,
+            streams,
+            stream_id: AtomicU64::new(0),
+            sock,
+            ctl,
+            liveness,
+            heartbeat: None,
             listener: Some(listener),
         })
     }
 
+    /// the features negotiated with the server
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
     /// set the default timeout value
-    /// the initial timeout is 10 seconds
+    /// there is no timeout initially, the calls wait until the rsp
+    /// arrives or the connection is closed. the timeout is sent to the
+    /// server as the deadline only when the `DEADLINE` feature is negotiated
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = Some(timeout);
     }
-}
 
-impl<S: StreamExt> Client for MultiplexClient<S> {
-    fn call_service(&self, req: ReqBuf) -> Result<Frame, Error> {
-        let waiter = TokenWaiter::new();
+    /// append a crc32c checksum to each request frame
+    /// the server would reply with checksummed frames as well.
+    /// it's left out when the `CHECKSUM` feature is not negotiated
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    /// append the checksum only when it's negotiated
+    fn use_checksum(&self) -> bool {
+        self.checksum && self.features.contains(Features::CHECKSUM)
+    }
+
+    /// send the timeout only when the deadline is negotiated
+    fn wire_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        timeout.filter(|_| self.features.contains(Features::DEADLINE))
+    }
+
+    /// the writer of the cancel frames, which are dropped if not negotiated
+    fn cancel_writer(&self) -> FrameWriter {
+        if !self.features.contains(Features::CANCEL) {
+            return Arc::new(|_| {});
+        }
+        let sock = self.sock.clone();
+        Arc::new(move |data| sock.write(data))
+    }
+
+    /// ping the server on each interval, if nothing is received from the
+    /// server within the timeout the connection is closed, and all the
+    /// waiting calls and streams fail with an `UNAVAILABLE` status
+    /// return `Error::Handshake` if the server doesn't support the heartbeat
+    pub fn set_heartbeat(&mut self, interval: Duration, timeout: Duration) -> Result<(), Error> {
+        if !self.features.contains(Features::HEARTBEAT) {
+            let s = "the server doesn't support heartbeat".to_owned();
+            error!("{s}");
+            return Err(Error::Handshake(s));
+        }
+        let sock = self.sock.clone();
+        let writer: FrameWriter = Arc::new(move |data| sock.write(data));
+        let closed = self.closed.clone();
+        let pending = self.pending.clone();
+        let streams = self.streams.clone();
+        let ctl = self.ctl.clone();
+        let on_dead = move || {
+            close_conn(&closed, &pending, &streams, || {
+                unavailable("heartbeat timeout")
+            });
+            // stop the listener
+            ctl.lock().unwrap().shutdown().ok();
+        };
+        // the old heartbeat is cancelled when dropped
+        let heartbeat =
+            Heartbeat::spawn(interval, timeout, self.liveness.clone(), writer, on_dead)?;
+        self.heartbeat = Some(heartbeat);
+        Ok(())
+    }
+
+    /// return true if the connection is closed, the calls would fail fast
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// return true if the server is going away, the new calls would fail
+    pub fn is_going_away(&self) -> bool {
+        self.going_away.load(Ordering::Acquire)
+    }
+
+    /// register a new stream and return its id and the rsp receiver
+    fn new_stream(&self) -> Result<(u64, RspReceiver), Error> {
+        if !self.features.contains(Features::STREAMING) {
+            let s = "the server doesn't support streaming".to_owned();
+            error!("{s}");
+            return Err(Error::Handshake(s));
+        }
+        if self.going_away.load(Ordering::Acquire) {
+            return Err(unavailable("server is going away"));
+        }
+
+        let id = STREAM_ID_BIT | self.stream_id.fetch_add(1, Ordering::Relaxed);
+        info!("stream request id = {:?}", id);
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut streams = self.streams.lock().unwrap();
+            // checked with the lock, so the stream is either failed by `close_conn` or rejected here
+            if self.is_closed() {
+                return Err(conn_closed());
+            }
+            streams.insert(id, tx);
+        }
+        // the receiver would unregister the stream when dropped
+        let writer = self.cancel_writer();
+        let receiver = RspReceiver::new(id, self.streams.clone(), rx, self.timeout, writer);
+        Ok((id, receiver))
+    }
+
+    /// call the server with a request that expects a streaming response
+    /// the response items can be received from the returned receiver
+    /// the timeout is applied for receiving each item
+    pub fn call_stream(&self, mut req: ReqBuf) -> Result<RspReceiver, Error> {
+        let (id, receiver) = self.new_stream()?;
+
+        // send the request
+        req.set_checksum(self.use_checksum());
+        req.set_max_len(self.max_frame_len);
+        req.set_max_msg_len(self.max_msg_len);
+        let timeout = req.timeout().or(self.timeout);
+        req.set_timeout(self.wire_timeout(timeout));
+        req.set_stream();
+        let buf = req.finish(id)?;
+        self.sock.write(buf);
+
+        Ok(receiver)
+    }
+
+    /// cancel the running stream
+    /// the server would abandon its service, and the stream receiver
+    /// would get `Error::Cancelled`. it's a no-op if the stream is done
+    pub fn cancel(&self, rx: &RspReceiver) {
+        if !rx.belongs_to(&self.streams) {
+            warn!("cancel a stream of another client, id = {:?}", rx.id());
+            return;
+        }
+        self.cancel_id(rx.id());
+    }
+
+    /// cancel the running call
+    /// the server would abandon its service, and the waiting `PendingCall::wait`
+    /// would return `Error::Cancelled`. it's a no-op if the call is done
+    pub fn cancel_call(&self, call: &PendingCall) {
+        if !Arc::ptr_eq(&call.pending, &self.pending) {
+            warn!("cancel a call of another client, id = {:?}", call.id);
+            return;
+        }
+        self.cancel_id(call.id);
+    }
+
+    /// fail the waiting call or stream and tell the server to abandon it
+    fn cancel_id(&self, id: u64) {
+        if !dispatch_rsp(&self.pending, &self.streams, id, Err(Error::Cancelled)) {
+            return;
+        }
+        if id & STREAM_ID_BIT != 0 {
+            // the receiver would not cancel it again when dropped
+            self.streams.lock().unwrap().remove(&id);
+        }
+        info!("cancel request id = {:?}", id);
+        (self.cancel_writer())(encode_control(id, Control::Cancel));
+    }
+
+    /// send a call to the server without waiting for the rsp
+    /// the rsp is waited by `PendingCall::wait`, and the call can be
+    /// cancelled by `cancel_call` in another coroutine
+    pub fn start_call(&self, mut req: ReqBuf) -> Result<PendingCall, Error> {
+        if self.going_away.load(Ordering::Acquire) {
+            return Err(unavailable("server is going away"));
+        }
+
+        let waiter = RspWaiter::new();
         let id = waiter.id().unwrap();
         info!("request id = {:?}", id);
 
         // send the request
         let id: usize = id.into();
-        let buf = req.finish(id as u64);
+        req.set_checksum(self.use_checksum());
+        req.set_max_len(self.max_frame_len);
+        req.set_max_msg_len(self.max_msg_len);
+        // the req timeout takes precedence over the client timeout
+        let timeout = req.timeout().or(self.timeout);
+        req.set_timeout(self.wire_timeout(timeout));
+        let buf = req.finish(id as u64)?;
+        {
+            let mut pending = self.pending.lock().unwrap();
+            // checked with the lock, so the call is either failed by `close_conn` or rejected here
+            if self.is_closed() {
+                return Err(conn_closed());
+            }
+            pending.insert(id as u64);
+        }
 
         self.sock.write(buf);
 
-        // wait for the rsp
-        Ok(waiter.wait_rsp(self.timeout)?)
+        Ok(PendingCall {
+            id: id as u64,
+            waiter,
+            timeout,
+            pending: self.pending.clone(),
+            writer: self.cancel_writer(),
+        })
+    }
+
+    /// open a bidirectional stream to the server
+    /// the request items are sent by the returned sender, and the response
+    /// items are received from the returned receiver, both halves can be used
+    /// in different coroutines. the server handles it by `Server::service_duplex`
+    pub fn open_stream(&self) -> Result<(ReqSender, RspReceiver), Error> {
+        let (id, receiver) = self.new_stream()?;
+        let sock = self.sock.clone();
+        let writer: FrameWriter = Arc::new(move |data| sock.write(data));
+        let sender = ReqSender::new(
+            id,
+            self.use_checksum(),
+            self.max_frame_len,
+            self.max_msg_len,
+            writer,
+        );
+        Ok((sender, receiver))
+    }
+}
+
+impl<S: StreamExt> Client for MultiplexClient<S> {
+    fn call_service(&self, req: ReqBuf) -> Result<Frame, Error> {
+        self.start_call(req)?.wait()
+    }
+}
+
+/// a call sent to the server, returned by `MultiplexClient::start_call`
+pub struct PendingCall {
+    id: u64,
+    waiter: RspWaiter,
+    timeout: Option<Duration>,
+    // the pending calls of the client
+    pending: Arc<Mutex<HashSet<u64>>>,
+    writer: FrameWriter,
+}
+
+impl fmt::Debug for PendingCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingCall")
+            .field("id", &self.id)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl PendingCall {
+    /// wait for the rsp of the call, the timeout of the call is applied
+    /// the call is done once it returns, don't wait it again
+    pub fn wait(&self) -> Result<Frame, Error> {
+        let ret = self.waiter.wait_rsp(self.timeout);
+        self.pending.lock().unwrap().remove(&self.id);
+        match ret {
+            Ok(rsp) => rsp,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::TimedOut {
+                    // nobody would read the rsp, let the server abandon it
+                    info!("request timeout, cancel id = {:?}", self.id);
+                    (self.writer)(encode_control(self.id, Control::Cancel));
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        // the waiter is gone, the rsp must not be delivered to it
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// the error of the calls on a closed connection
+fn conn_closed() -> Error {
+    let e = io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed");
+    Error::Io(e)
+}
+
+/// the error of the calls that can't be served by the connection
+fn unavailable(message: &str) -> Error {
+    Error::Status {
+        code: StatusCode::UNAVAILABLE,
+        message: message.to_owned(),
+        details: Vec::new(),
     }
 }
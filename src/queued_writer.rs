@@ -1,10 +1,18 @@
-use std::io::Write;
+use std::fmt;
+use std::io::{self, Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use bytes::BytesMut;
 use may::queue::mpsc::Queue;
 use may::sync::Mutex;
 
+// send the encoded frame to the peer
+pub(crate) type FrameWriter = Arc<dyn Fn(Vec<u8>) + Send + Sync>;
+
+// called when the queued data failed to be written
+type ErrorHandler = Box<dyn Fn(io::Error) + Send + Sync>;
+
 #[derive(Debug)]
 struct BufWriter<W: Write> {
     writer: W,
@@ -34,19 +42,28 @@ impl<W: Write> BufWriter<W> {
     }
 }
 
-#[derive(Debug)]
 pub struct QueuedWriter<W: Write> {
     data_count: AtomicUsize,
     data_queue: Queue<Vec<u8>>,
     writer: Mutex<BufWriter<W>>,
+    on_error: ErrorHandler,
 }
 
 impl<W: Write> QueuedWriter<W> {
     pub fn new(writer: W) -> Self {
+        Self::with_error_handler(writer, |e| error!("QueuedWriter failed, err={}", e))
+    }
+
+    /// the handler is called with the error when the queued data failed to be written
+    pub fn with_error_handler<F>(writer: W, on_error: F) -> Self
+    where
+        F: Fn(io::Error) + Send + Sync + 'static,
+    {
         QueuedWriter {
             data_count: AtomicUsize::new(0),
             data_queue: Queue::new(),
             writer: Mutex::new(BufWriter::new(writer)),
+            on_error: Box::new(on_error),
         }
     }
 
@@ -73,9 +90,17 @@ impl<W: Write> QueuedWriter<W> {
             }
 
             if let Err(e) = writer.write_all() {
-                // FIXME: handle the error
-                error!("QueuedWriter failed, err={}", e);
+                (self.on_error)(e);
             }
         }
     }
 }
+
+impl<W: Write + fmt::Debug> fmt::Debug for QueuedWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueuedWriter")
+            .field("data_count", &self.data_count)
+            .field("writer", &self.writer)
+            .finish_non_exhaustive()
+    }
+}
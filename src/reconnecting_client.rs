@@ -0,0 +1,353 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::{Error, StatusCode};
+use crate::frame::{Frame, ReqBuf, FRAME_MAX_LEN, MSG_MAX_LEN};
+use crate::handshake::Features;
+use crate::multiplex_client::MultiplexClient;
+use crate::stream::{ReqSender, RspReceiver};
+use crate::stream_ext::StreamExt;
+use crate::Client;
+
+use may::net::TcpStream;
+use may::sync::Mutex;
+
+// the connector gets the connect timeout
+type Connector<S> = Box<dyn Fn(Option<Duration>) -> io::Result<S> + Send + Sync>;
+type StateHandler = Box<dyn Fn(ConnState) + Send + Sync>;
+
+/// the connection state of a `ReconnectingClient`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    /// connecting to the server
+    Connecting,
+    /// the connection is ready for the calls
+    Connected,
+    /// not connected, either the connection is lost or failed to connect
+    Disconnected,
+}
+
+/// the current connection and the backoff of the reconnecting
+struct Conn<S: StreamExt> {
+    client: Option<Arc<MultiplexClient<S>>>,
+    state: ConnState,
+    // the backoff of the last failed connect
+    backoff: Duration,
+    // the next connect is not tried before this
+    retry_at: Option<Instant>,
+}
+
+impl<S: StreamExt> Conn<S> {
+    /// record the new state, the change is collected to be notified
+    fn set_state(&mut self, state: ConnState, changes: &mut Vec<ConnState>) {
+        if self.state != state {
+            self.state = state;
+            changes.push(state);
+        }
+    }
+}
+
+/// a `MultiplexClient` that connects lazily and reconnects once the connection is lost
+///
+/// the connection is made by the first call, and remade by the next call after
+/// the connection is closed or the server is going away. a failed connect is not
+/// retried before the backoff, which is doubled for each failure up to the max.
+/// the calls during the backoff fail fast with an `UNAVAILABLE` status, wrap the
+/// client with the `Retry` layer to wait for the connection
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use conetty::{Client, ReconnectingClient, ReqBuf};
+/// let client = ReconnectingClient::tcp(("127.0.0.1", 4000))
+///     .unwrap()
+///     .backoff(Duration::from_millis(100), Duration::from_secs(10))
+///     .on_state(|state| println!("connection state: {state:?}"));
+/// let rsp = client.call_service(ReqBuf::new());
+/// ```
+pub struct ReconnectingClient<S: StreamExt> {
+    connector: Connector<S>,
+    // the options applied to each new connection
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    checksum: bool,
+    heartbeat: Option<(Duration, Duration)>,
+    features: Features,
+    // the range of the backoff
+    min_backoff: Duration,
+    max_backoff: Duration,
+    // the ratio of the backoff that is randomly reduced
+    jitter: f64,
+    on_state: Option<StateHandler>,
+    conn: Mutex<Conn<S>>,
+    // held while connecting, so that only one connect is made at a time
+    // and the `conn` lock is not held by the slow connect
+    connecting: Mutex<()>,
+}
+
+impl<S: StreamExt> fmt::Debug for ReconnectingClient<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("connect_timeout", &self.connect_timeout)
+            .field("timeout", &self.timeout)
+            .field("checksum", &self.checksum)
+            .field("heartbeat", &self.heartbeat)
+            .field("features", &self.features)
+            .field("min_backoff", &self.min_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl ReconnectingClient<TcpStream> {
+    /// create a client that connects to the tcp address
+    /// the address is resolved here, but not connected until the first call
+    pub fn tcp<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        Ok(Self::with_connector(Box::new(move |timeout| {
+            connect_tcp(&addrs, timeout)
+        })))
+    }
+}
+
+/// connect to the first reachable address within the timeout
+fn connect_tcp(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return TcpStream::connect(addrs),
+    };
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(s) => return Ok(s),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect")))
+}
+
+impl<S: StreamExt> ReconnectingClient<S> {
+    /// create a client that makes the connections by the connector
+    /// the connect timeout only applies to the handshake, the connector should
+    /// bound the time of its own connect
+    pub fn new<F>(connector: F) -> Self
+    where
+        F: Fn() -> io::Result<S> + Send + Sync + 'static,
+    {
+        Self::with_connector(Box::new(move |_| connector()))
+    }
+
+    fn with_connector(connector: Connector<S>) -> Self {
+        ReconnectingClient {
+            connector,
+            connect_timeout: Some(Duration::from_secs(10)),
+            timeout: None,
+            checksum: false,
+            heartbeat: None,
+            features: Features::all(),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.2,
+            on_state: None,
+            conn: Mutex::new(Conn {
+                client: None,
+                state: ConnState::Disconnected,
+                backoff: Duration::ZERO,
+                retry_at: None,
+            }),
+            connecting: Mutex::new(()),
+        }
+    }
+
+    /// set the timeout of making a connection, including the handshake
+    /// a connect that times out is retried after the backoff, the default is 10s
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// set the timeout of the calls, see `MultiplexClient::set_timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// append a crc32c checksum to each request frame
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// ping the server, see `MultiplexClient::set_heartbeat`
+    /// a connection that fails the heartbeat is remade by the next call
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// offer only the features to the server, see `MultiplexClient::with_features`
+    pub fn features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// set the range of the backoff after a failed connect
+    /// the default is from 100ms to 10s
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// set the ratio of the backoff that is randomly reduced, in `0.0..=1.0`
+    /// so that the clients don't reconnect at the same time, the default is 0.2
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// set the handler of the connection state changes
+    /// it's called in the coroutine of the call that changes the state
+    pub fn on_state<F>(mut self, f: F) -> Self
+    where
+        F: Fn(ConnState) + Send + Sync + 'static,
+    {
+        self.on_state = Some(Box::new(f));
+        self
+    }
+
+    /// the current connection state
+    pub fn state(&self) -> ConnState {
+        self.conn.lock().unwrap().state
+    }
+
+    /// call the server with a request that expects a streaming response
+    /// see `MultiplexClient::call_stream`
+    pub fn call_stream(&self, req: ReqBuf) -> Result<RspReceiver, Error> {
+        self.client()?.call_stream(req)
+    }
+
+    /// open a bidirectional stream to the server
+    /// see `MultiplexClient::open_stream`
+    pub fn open_stream(&self) -> Result<(ReqSender, RspReceiver), Error> {
+        self.client()?.open_stream()
+    }
+
+    /// get the alive connection, or make a new one
+    fn client(&self) -> Result<Arc<MultiplexClient<S>>, Error> {
+        let mut changes = Vec::new();
+        let ret = self.client_inner(&mut changes);
+        // the handler may call the client, don't hold the lock
+        if let Some(f) = self.on_state.as_ref() {
+            changes.into_iter().for_each(f);
+        }
+        ret
+    }
+
+    fn client_inner(&self, changes: &mut Vec<ConnState>) -> Result<Arc<MultiplexClient<S>>, Error> {
+        if let Some(ret) = self.check_conn(&mut self.conn.lock().unwrap(), changes) {
+            return ret;
+        }
+
+        // the other calls wait here for the running connect
+        let _connecting = self.connecting.lock().unwrap();
+        {
+            // the connection may be made or failed by another call
+            let mut conn = self.conn.lock().unwrap();
+            if let Some(ret) = self.check_conn(&mut conn, changes) {
+                return ret;
+            }
+            conn.set_state(ConnState::Connecting, changes);
+        }
+
+        // don't hold the lock while connecting, the connect may take long
+        let ret = self.connect();
+        let mut conn = self.conn.lock().unwrap();
+        match ret {
+            Ok(client) => {
+                info!("reconnecting client: connected");
+                let client = Arc::new(client);
+                conn.client = Some(client.clone());
+                conn.backoff = Duration::ZERO;
+                conn.retry_at = None;
+                conn.set_state(ConnState::Connected, changes);
+                Ok(client)
+            }
+            Err(e) => {
+                conn.backoff = (conn.backoff * 2).clamp(self.min_backoff, self.max_backoff);
+                let backoff = jitter(conn.backoff, self.jitter);
+                warn!("reconnecting client: connect err = {e}, retry after {backoff:?}");
+                conn.retry_at = Some(Instant::now() + backoff);
+                conn.set_state(ConnState::Disconnected, changes);
+                Err(e)
+            }
+        }
+    }
+
+    /// return the alive connection, or the error during the backoff
+    /// return none if a new connection should be made
+    fn check_conn(
+        &self,
+        conn: &mut Conn<S>,
+        changes: &mut Vec<ConnState>,
+    ) -> Option<Result<Arc<MultiplexClient<S>>, Error>> {
+        if let Some(client) = conn.client.as_ref() {
+            if !client.is_closed() && !client.is_going_away() {
+                return Some(Ok(client.clone()));
+            }
+            info!("reconnecting client: connection lost");
+            conn.client = None;
+            conn.set_state(ConnState::Disconnected, changes);
+        }
+
+        let now = Instant::now();
+        match conn.retry_at {
+            Some(retry_at) if now < retry_at => Some(Err(Error::Status {
+                code: StatusCode::UNAVAILABLE,
+                message: format!("not connected, retry after {:?}", retry_at - now),
+                details: Vec::new(),
+            })),
+            _ => None,
+        }
+    }
+
+    /// make a new connection with the options
+    fn connect(&self) -> Result<MultiplexClient<S>, Error> {
+        let stream = (self.connector)(self.connect_timeout)?;
+        let max_msg_len = MSG_MAX_LEN.max(FRAME_MAX_LEN);
+        let mut client = MultiplexClient::connect(
+            stream,
+            FRAME_MAX_LEN,
+            max_msg_len,
+            self.connect_timeout,
+            self.features,
+        )?;
+        if let Some(timeout) = self.timeout {
+            client.set_timeout(timeout);
+        }
+        client.set_checksum(self.checksum);
+        if let Some((interval, timeout)) = self.heartbeat {
+            client.set_heartbeat(interval, timeout)?;
+        }
+        Ok(client)
+    }
+}
+
+impl<S: StreamExt> Client for ReconnectingClient<S> {
+    fn call_service(&self, req: ReqBuf) -> Result<Frame, Error> {
+        self.client()?.call_service(req)
+    }
+}
+
+/// randomly reduce the backoff by at most the ratio
+fn jitter(backoff: Duration, ratio: f64) -> Duration {
+    let r = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    backoff.mul_f64(1.0 - ratio * r)
+}
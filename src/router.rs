@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::context::RequestContext;
+use crate::errors::{Error, StatusCode, WireError};
+use crate::frame::RspBuf;
+use crate::Server;
+
+use may::sync::RwLock;
+
+/// the metadata key of the method name, set by `ReqBuf::set_method`
+pub const METHOD_KEY: &str = ":method";
+/// the metadata key of the method id, set by `ReqBuf::set_method_id`
+pub const METHOD_ID_KEY: &str = ":method-id";
+
+/// the method id of a method name, which is the FNV-1a hash of the name
+///
+/// the `service!` macro uses `"<service>.<method>"` as the name
+pub const fn method_id(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+type Handler =
+    Arc<dyn Fn(&RequestContext, &[u8], &mut RspBuf) -> Result<(), WireError> + Send + Sync>;
+
+/// the routes indexed by name, and the method ids of the names
+#[derive(Default)]
+struct Routes {
+    handlers: HashMap<String, Handler>,
+    ids: HashMap<u64, String>,
+}
+
+/// route the requests to the registered services by the method in the frame metadata
+///
+/// a route is registered by name, and the request is routed by its method name
+/// or method id. a name whose method id collides with a registered name is
+/// rejected, so that a method id always routes to one name. a method name like
+/// `"calc.add"` that has no route of its own falls back to the route of its
+/// service `"calc"`, so a whole `service!` can be registered under its module
+/// name. unknown methods are replied with an `UNIMPLEMENTED` status
+///
+/// the router is cheap to clone, the routes can be added or removed at runtime
+/// through a clone while the server is running
+///
+/// only the unary requests are routed, streaming requests are not supported
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Arc<RwLock<Routes>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// register the server under the name, return true if an old route is replaced
+    /// return `Error::MethodIdConflict` if the method id of the name collides with another route
+    pub fn add<S: Server>(&self, name: &str, server: S) -> Result<bool, Error> {
+        let server = Arc::new(server);
+        self.add_fn(name, move |ctx, req, rsp| {
+            server.service_with_context(ctx, req, rsp)
+        })
+    }
+
+    /// register the handler under the name, return true if an old route is replaced
+    /// return `Error::MethodIdConflict` if the method id of the name collides with another route
+    pub fn add_fn<F>(&self, name: &str, f: F) -> Result<bool, Error>
+    where
+        F: Fn(&RequestContext, &[u8], &mut RspBuf) -> Result<(), WireError> + Send + Sync + 'static,
+    {
+        let mut routes = self.routes.write().unwrap();
+        let id = method_id(name);
+        if let Some(other) = routes.ids.get(&id) {
+            if other != name {
+                error!("router method id conflict. name={name}, other={other}");
+                return Err(Error::MethodIdConflict {
+                    name: name.to_owned(),
+                    other: other.clone(),
+                });
+            }
+        }
+        routes.ids.insert(id, name.to_owned());
+        match routes.handlers.insert(name.to_owned(), Arc::new(f)) {
+            Some(_) => {
+                warn!("router route updated. name={name}");
+                Ok(true)
+            }
+            None => {
+                info!("router route added. name={name}");
+                Ok(false)
+            }
+        }
+    }
+
+    /// remove the route of the name, return true if it exists
+    pub fn remove(&self, name: &str) -> bool {
+        let mut routes = self.routes.write().unwrap();
+        if routes.handlers.remove(name).is_none() {
+            return false;
+        }
+        routes.ids.remove(&method_id(name));
+        info!("router route removed. name={name}");
+        true
+    }
+
+    /// return true if the name is registered
+    pub fn contains(&self, name: &str) -> bool {
+        self.routes.read().unwrap().handlers.contains_key(name)
+    }
+
+    /// the names of the registered routes
+    pub fn names(&self) -> Vec<String> {
+        let routes = self.routes.read().unwrap();
+        routes.handlers.keys().cloned().collect()
+    }
+
+    /// find the handler of the request
+    fn route(&self, ctx: &RequestContext) -> Result<Handler, WireError> {
+        let md = ctx.metadata();
+        let name = md.get(METHOD_KEY).and_then(|v| std::str::from_utf8(v).ok());
+        let id = match md.get(METHOD_ID_KEY) {
+            Some(id) => match <[u8; 8]>::try_from(id) {
+                Ok(id) => Some(u64::from_be_bytes(id)),
+                Err(_) => {
+                    let s = "invalid method id".to_owned();
+                    error!("{s}");
+                    return Err(WireError::ServerDeserialize(s));
+                }
+            },
+            None => name.map(method_id),
+        };
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let s = "the request has no method".to_owned();
+                error!("{s}");
+                return Err(WireError::status(StatusCode::UNIMPLEMENTED, s));
+            }
+        };
+
+        let routes = self.routes.read().unwrap();
+        let handler = match name {
+            Some(name) => routes.handlers.get(name),
+            None => routes.ids.get(&id).and_then(|n| routes.handlers.get(n)),
+        };
+        if let Some(handler) = handler {
+            return Ok(handler.clone());
+        }
+        // fall back to the service of the method
+        if let Some((service, _)) = name.and_then(|n| n.rsplit_once('.')) {
+            if let Some(handler) = routes.handlers.get(service) {
+                return Ok(handler.clone());
+            }
+        }
+
+        let s = match name {
+            Some(name) => format!("unknown method. name={name}"),
+            None => format!("unknown method. id={id:#x}"),
+        };
+        error!("{s}");
+        Err(WireError::status(StatusCode::UNIMPLEMENTED, s))
+    }
+}
+
+impl Server for Router {
+    fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+        // only reached by the streaming requests, the routes only serve unary requests
+        let s = "streaming requests are not routed".to_owned();
+        error!("{s}");
+        Err(WireError::status(StatusCode::UNIMPLEMENTED, s))
+    }
+
+    fn service_with_context(
+        &self,
+        ctx: &RequestContext,
+        req: &[u8],
+        rsp: &mut RspBuf,
+    ) -> Result<(), WireError> {
+        // don't hold the lock while serving
+        let handler = self.route(ctx)?;
+        handler(ctx, req, rsp)
+    }
+}
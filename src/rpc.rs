@@ -0,0 +1,207 @@
+use std::io::Write;
+
+use crate::codec::{self, Bincode, Codec};
+use crate::errors::{Error, StatusCode, WireError};
+use crate::frame::{Frame, ReqBuf, RspBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// typed req layout
+// method_id(u64) + args(tuple encoded by the bincode codec)
+// the rsp is the return value encoded by the bincode codec
+
+/// encode the typed req, the method name is also set for the `Router`
+#[doc(hidden)]
+pub fn encode_req<A: Serialize + DeserializeOwned>(
+    id: u64,
+    method: &str,
+    args: &A,
+) -> Result<ReqBuf, Error> {
+    let mut req = ReqBuf::new();
+    req.set_method(method);
+    req.write_all(&id.to_be_bytes())
+        .map_err(|e| Error::ClientSerialize(e.to_string()))?;
+    Bincode
+        .encode(args, &mut req)
+        .map_err(|e| Error::ClientSerialize(e.to_string()))?;
+    Ok(req)
+}
+
+/// decode the typed rsp
+#[doc(hidden)]
+pub fn decode_rsp<R: Serialize + DeserializeOwned>(frame: &Frame) -> Result<R, Error> {
+    codec::decode_rsp(&Bincode, frame.decode_rsp()?)
+}
+
+/// split the typed req into the method id and the encoded args
+#[doc(hidden)]
+pub fn split_req(req: &[u8]) -> Result<(u64, &[u8]), WireError> {
+    if req.len() < 8 {
+        let s = format!("invalid typed request. len={}", req.len());
+        error!("{s}");
+        return Err(WireError::ServerDeserialize(s));
+    }
+    let (id, args) = req.split_at(8);
+    Ok((u64::from_be_bytes(id.try_into().unwrap()), args))
+}
+
+/// decode the args of the typed req
+#[doc(hidden)]
+pub fn decode_args<A: Serialize + DeserializeOwned>(args: &[u8]) -> Result<A, WireError> {
+    codec::decode_req(&Bincode, args)
+}
+
+/// encode the return value into the rsp
+#[doc(hidden)]
+pub fn encode_rsp<R: Serialize + DeserializeOwned>(
+    rsp: &mut RspBuf,
+    ret: &R,
+) -> Result<(), WireError> {
+    codec::encode_rsp(&Bincode, rsp, ret)
+}
+
+/// the error of an unknown method id
+#[doc(hidden)]
+pub fn unknown_method(service: &str, id: u64) -> WireError {
+    let s = format!("unknown method of {service}. id={id:#x}");
+    error!("{s}");
+    WireError::status(StatusCode::UNIMPLEMENTED, s)
+}
+
+/// define a typed rpc service
+///
+/// the macro generates a module with the same name that contains
+/// - `Service`: the trait of the typed methods, impl it for your server
+/// - `Server<T>`: wraps a `Service` impl into a `conetty::Server`
+/// - `Client<C>`: the typed client over any `conetty::Client`
+/// - `method`, `method_name`: the method id and name constants
+///
+/// the server can be registered into a `Router` under the module name
+///
+/// the macro uses the bincode codec, so it's only available with the `bincode` feature
+///
+/// the args and return values must impl serde `Serialize` and `Deserialize`,
+/// a method can return an `Err(WireError)` to report a status to the client
+///
+/// ```ignore
+/// conetty::service! {
+///     pub mod calc {
+///         fn echo(data: String) -> String;
+///         fn add(x: u32, y: u32) -> u32;
+///     }
+/// }
+///
+/// struct Calc;
+///
+/// impl calc::Service for Calc {
+///     fn echo(&self, data: String) -> Result<String, WireError> {
+///         Ok(data)
+///     }
+///
+///     fn add(&self, x: u32, y: u32) -> Result<u32, WireError> {
+///         Ok(x + y)
+///     }
+/// }
+///
+/// let _server = calc::Server(Calc).start(addr)?;
+/// let client = calc::Client::new(MultiplexClient::new(stream)?);
+/// assert_eq!(client.add(1, 2)?, 3);
+/// ```
+#[cfg(feature = "bincode")]
+#[macro_export]
+macro_rules! service {
+    (
+        $(#[$attr: meta])*
+        $vis: vis mod $name: ident {
+            $(
+                $(#[$method_attr: meta])*
+                fn $method: ident($($arg: ident: $ty: ty),* $(,)?) -> $ret: ty;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        $vis mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            /// the method names of the service
+            pub mod method_name {
+                $(
+                    #[allow(non_upper_case_globals)]
+                    pub const $method: &str = concat!(stringify!($name), ".", stringify!($method));
+                )*
+            }
+
+            /// the method ids of the service
+            pub mod method {
+                $(
+                    #[allow(non_upper_case_globals)]
+                    pub const $method: u64 = $crate::method_id(super::method_name::$method);
+                )*
+            }
+
+            /// the typed methods of the service
+            pub trait Service: Send + Sync + 'static {
+                $(
+                    $(#[$method_attr])*
+                    fn $method(&self, $($arg: $ty),*) -> Result<$ret, $crate::WireError>;
+                )*
+            }
+
+            /// dispatch the typed requests to the `Service` impl
+            pub struct Server<T>(pub T);
+
+            impl<T: Service> $crate::Server for Server<T> {
+                fn service(
+                    &self,
+                    req: &[u8],
+                    rsp: &mut $crate::RspBuf,
+                ) -> Result<(), $crate::WireError> {
+                    let (id, _args) = $crate::rpc::split_req(req)?;
+                    $(
+                        if id == method::$method {
+                            let ($($arg,)*): ($($ty,)*) = $crate::rpc::decode_args(_args)?;
+                            let ret = self.0.$method($($arg),*)?;
+                            return $crate::rpc::encode_rsp(rsp, &ret);
+                        }
+                    )*
+                    Err($crate::rpc::unknown_method(stringify!($name), id))
+                }
+            }
+
+            /// the typed client of the service
+            pub struct Client<C> {
+                inner: C,
+            }
+
+            impl<C: $crate::Client> Client<C> {
+                pub fn new(inner: C) -> Self {
+                    Client { inner }
+                }
+
+                /// the underlying client
+                pub fn inner(&self) -> &C {
+                    &self.inner
+                }
+
+                pub fn into_inner(self) -> C {
+                    self.inner
+                }
+
+                $(
+                    $(#[$method_attr])*
+                    pub fn $method(&self, $($arg: $ty),*) -> Result<$ret, $crate::Error> {
+                        let req = $crate::rpc::encode_req(
+                            method::$method,
+                            method_name::$method,
+                            &($($arg,)*),
+                        )?;
+                        let frame = self.inner.call_service(req)?;
+                        $crate::rpc::decode_rsp(&frame)
+                    }
+                )*
+            }
+        }
+    };
+}
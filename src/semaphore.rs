@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use may::sync::{Condvar, Mutex};
+
+/// counting semaphore that can be used across coroutines
+#[derive(Debug)]
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    cond: Condvar,
+}
+
+/// the permit would be returned to the semaphore when dropped
+#[derive(Debug)]
+pub struct Permit(Arc<Semaphore>);
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Arc<Self> {
+        Arc::new(Semaphore {
+            permits: Mutex::new(permits),
+            cond: Condvar::new(),
+        })
+    }
+
+    /// block until a permit is available
+    pub fn acquire(self: &Arc<Self>) -> Permit {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.cond.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        Permit(self.clone())
+    }
+
+    /// get a permit if there is any available
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut permits = self.permits.lock().unwrap();
+        if *permits == 0 {
+            return None;
+        }
+        *permits -= 1;
+        Some(Permit(self.clone()))
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut permits = self.0.permits.lock().unwrap();
+        *permits += 1;
+        self.0.cond.notify_one();
+    }
+}
@@ -1,87 +1,1318 @@
-use std::io::{self, BufReader, Cursor};
-use std::net::ToSocketAddrs;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::net::{IpAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
 #[cfg(unix)]
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::frame::{Frame, RspBuf};
-use crate::queued_writer::QueuedWriter;
+use crate::builder::{ConnectionPolicy, InflightPolicy, ServerBuilder, ServerConfig};
+use crate::context::{Peer, RequestContext};
+use crate::errors::{Error, StatusCode, WireError};
+use crate::frame::{encode_control, Control, Frame, RspBuf, UDP_MAX_LEN};
+use crate::handshake::{server_handshake, Features};
+use crate::heartbeat::{Heartbeat, Liveness};
+use crate::queued_writer::{FrameWriter, QueuedWriter};
+use crate::semaphore::{Permit, Semaphore};
+use crate::stream::{ReqReceiver, RspSender};
+use crate::stream_ext::StreamExt;
 use crate::Server;
 
 use co_managed::Manager;
 use may::net::{TcpListener, UdpSocket};
 #[cfg(unix)]
 use may::os::unix::net::UnixListener;
-use may::sync::Mutex;
+use may::sync::{mpsc, Mutex};
 use may::{coroutine, go};
 
+// the backoff range of the accept errors
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// state shared by the server instance and the running coroutines
+pub(crate) struct ServerState {
+    // set when the server starts shutting down
+    shutdown: AtomicBool,
+    // key generator for connections and requests
+    seq: AtomicU64,
+    // the alive connections, used to notify the clients when shutdown
+    conns: Mutex<HashMap<u64, Conn>>,
+    // the running requests
+    requests: Mutex<HashMap<u64, Running>>,
+    // number of the requests that are done and removed
+    completed: AtomicU64,
+    // number of the requests whose service panicked
+    panics: AtomicU64,
+    // the alive connections of each peer ip, when limited
+    peers: Mutex<HashMap<IpAddr, usize>>,
+    // number of the connections rejected by the limits
+    rejected_conns: AtomicU64,
+    // number of the connections closed by the timeouts
+    idle_timeouts: AtomicU64,
+    read_timeouts: AtomicU64,
+    write_timeouts: AtomicU64,
+    heartbeat_timeouts: AtomicU64,
+    // the inflight limit of the whole server
+    inflight: Option<Arc<Semaphore>>,
+    inflight_policy: InflightPolicy,
+}
+
+/// the inflight permits of a running request, released when dropped
+struct InflightPermit {
+    _conn: Option<Permit>,
+    _total: Option<Permit>,
+}
+
+/// an alive connection of the server
+#[derive(Clone)]
+struct Conn {
+    writer: FrameWriter,
+    // shutdown the stream, which also fails a blocked write
+    close: Arc<dyn Fn() + Send + Sync>,
+}
+
+/// remove the connection from the server state when dropped
+struct ConnGuard {
+    state: Arc<ServerState>,
+    key: u64,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.state.conns.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// release the connection of the peer ip when dropped
+struct PeerGuard {
+    state: Arc<ServerState>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            let mut peers = self.state.peers.lock().unwrap();
+            if let Some(n) = peers.get_mut(&ip) {
+                *n -= 1;
+                if *n == 0 {
+                    peers.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// a running request of the server
+#[derive(Default)]
+struct Running {
+    // the coroutine of the service, filled in once spawned
+    co: Option<coroutine::Coroutine>,
+    // set once the service returns, then only the rsp is written
+    done: bool,
+    // the service is writing a rsp item
+    writing: bool,
+    // the cancel is requested
+    cancelled: bool,
+}
+
+impl Running {
+    /// cancel the service coroutine, return false if it's not cancelled now
+    ///
+    /// a coroutine cancelled in the middle of a write would break the
+    /// connection, so the cancel is deferred until the item is written,
+    /// and the rsp of a returned service is never cancelled
+    fn cancel(&mut self) -> bool {
+        self.cancelled = true;
+        if self.done || self.writing {
+            return false;
+        }
+        // the one not spawned yet is cancelled once tracked
+        if let Some(co) = self.co.as_ref() {
+            unsafe { co.cancel() };
+        }
+        true
+    }
+}
+
+/// remove the request from the server state when dropped
+struct RequestGuard {
+    state: Arc<ServerState>,
+    key: u64,
+}
+
+impl RequestGuard {
+    /// mark the service returned, the rsp written after this can't be cancelled
+    fn finish(&self) {
+        if let Some(r) = self.state.requests.lock().unwrap().get_mut(&self.key) {
+            r.done = true;
+            r.co = None;
+        }
+    }
+
+    /// wrap the writer used by the service, so that it's not cancelled in
+    /// the middle of a write. the items are dropped once it's cancelled
+    fn writer(&self, writer: &FrameWriter) -> FrameWriter {
+        let state = self.state.clone();
+        let key = self.key;
+        let writer = writer.clone();
+        Arc::new(move |data| {
+            if !state.begin_write(key) {
+                return;
+            }
+            writer(data);
+            state.end_write(key);
+        })
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let request = self.state.requests.lock().unwrap().remove(&self.key);
+        // the cancelled ones are unwound before the service returns
+        if request.is_some_and(|r| r.done) {
+            self.state.completed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// a running request of a connection
+struct ConnRequest {
+    // the request key, filled in once spawned
+    key: Option<u64>,
+    // set when the client cancels the request
+    cancelled: Arc<AtomicBool>,
+}
+
+// the running requests of a connection, indexed by the frame id
+type ConnRequests = Arc<Mutex<HashMap<u64, ConnRequest>>>;
+
+/// remove the request from the connection when dropped
+struct ConnRequestGuard {
+    requests: ConnRequests,
+    id: u64,
+}
+
+impl Drop for ConnRequestGuard {
+    fn drop(&mut self) {
+        self.requests.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ServerState {
+    fn new(config: &ServerConfig) -> Arc<Self> {
+        Arc::new(ServerState {
+            shutdown: AtomicBool::new(false),
+            seq: AtomicU64::new(0),
+            conns: Mutex::new(HashMap::new()),
+            requests: Mutex::new(HashMap::new()),
+            completed: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+            peers: Mutex::new(HashMap::new()),
+            rejected_conns: AtomicU64::new(0),
+            idle_timeouts: AtomicU64::new(0),
+            read_timeouts: AtomicU64::new(0),
+            write_timeouts: AtomicU64::new(0),
+            heartbeat_timeouts: AtomicU64::new(0),
+            inflight: config.max_inflight_total.map(Semaphore::new),
+            inflight_policy: config.inflight_policy,
+        })
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    fn add_conn(self: &Arc<Self>, conn: Conn) -> ConnGuard {
+        let key = self.seq.fetch_add(1, Ordering::Relaxed);
+        self.conns.lock().unwrap().insert(key, conn);
+        ConnGuard {
+            state: self.clone(),
+            key,
+        }
+    }
+
+    /// count the connection of the peer ip, return none if the ip reaches the limit
+    fn add_peer(self: &Arc<Self>, peer: Peer, max: Option<usize>) -> Option<PeerGuard> {
+        let ip = match (peer, max) {
+            (Peer::Inet(addr), Some(max)) => {
+                let mut peers = self.peers.lock().unwrap();
+                let n = peers.entry(addr.ip()).or_insert(0);
+                if *n >= max {
+                    return None;
+                }
+                *n += 1;
+                Some(addr.ip())
+            }
+            _ => None,
+        };
+        Some(PeerGuard {
+            state: self.clone(),
+            ip,
+        })
+    }
+
+    fn add_request(self: &Arc<Self>) -> RequestGuard {
+        let key = self.seq.fetch_add(1, Ordering::Relaxed);
+        self.requests
+            .lock()
+            .unwrap()
+            .insert(key, Running::default());
+        RequestGuard {
+            state: self.clone(),
+            key,
+        }
+    }
+
+    /// record the coroutine of the request, if it's still running
+    fn track_request(&self, key: u64, co: &coroutine::Coroutine) {
+        if let Some(r) = self.requests.lock().unwrap().get_mut(&key) {
+            if !r.done {
+                r.co = Some(co.clone());
+            }
+            // cancelled before spawned
+            if r.cancelled {
+                r.cancel();
+            }
+        }
+    }
+
+    /// mark the request is writing a rsp item, return false if it's cancelled
+    fn begin_write(&self, key: u64) -> bool {
+        match self.requests.lock().unwrap().get_mut(&key) {
+            Some(r) if r.cancelled && !r.done => false,
+            Some(r) => {
+                r.writing = true;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// the deferred cancel takes effect once the item is written
+    fn end_write(&self, key: u64) {
+        if let Some(r) = self.requests.lock().unwrap().get_mut(&key) {
+            r.writing = false;
+            if r.cancelled {
+                r.cancel();
+            }
+        }
+    }
+
+    /// run the request in a new coroutine and track it, return the request key
+    fn spawn_request<F>(self: &Arc<Self>, config: &ServerConfig, f: F) -> u64
+    where
+        F: FnOnce(&RequestGuard) + Send + 'static,
+    {
+        let request = self.add_request();
+        let key = request.key;
+        let ret = go!(config.co_builder("service"), move || { f(&request) });
+        match ret {
+            Ok(h) => self.track_request(key, h.coroutine()),
+            Err(e) => error!("server spawn service: err = {:?}", e),
+        }
+        key
+    }
+
+    /// run the service and catch its panic, which is replied as an `INTERNAL` status
+    ///
+    /// the coroutine cancellation of may is also a panic, so the panic of a
+    /// cancelled request is resumed to finish its coroutine
+    fn catch_panic<F>(&self, key: u64, id: u64, f: F) -> Result<(), WireError>
+    where
+        F: FnOnce() -> Result<(), WireError>,
+    {
+        let e = match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(ret) => return ret,
+            Err(e) => e,
+        };
+        let cancelled = match self.requests.lock().unwrap().get(&key) {
+            Some(r) => r.cancelled,
+            None => false,
+        };
+        if cancelled {
+            panic::resume_unwind(e);
+        }
+        let msg = match e.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => match e.downcast_ref::<String>() {
+                Some(s) => s.clone(),
+                None => "unknown panic payload".to_owned(),
+            },
+        };
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        error!("service panicked: id={id}, err = {msg}");
+        Err(WireError::status(
+            StatusCode::INTERNAL,
+            format!("service panicked: {msg}"),
+        ))
+    }
+
+    /// get the inflight permits of a new request from the connection and the server limits
+    ///
+    /// wait for the permits with the backpressure policy, or return none
+    /// if any limit is reached with the reject policy
+    fn acquire_inflight(&self, conn: Option<&Arc<Semaphore>>) -> Option<InflightPermit> {
+        let acquire = |s: &Arc<Semaphore>| match self.inflight_policy {
+            InflightPolicy::Backpressure => Some(s.acquire()),
+            InflightPolicy::Reject => s.try_acquire(),
+        };
+        let conn = match conn.map(acquire) {
+            Some(None) => return None,
+            permit => permit.flatten(),
+        };
+        let total = match self.inflight.as_ref().map(acquire) {
+            Some(None) => return None,
+            permit => permit.flatten(),
+        };
+        Some(InflightPermit {
+            _conn: conn,
+            _total: total,
+        })
+    }
+
+    /// cancel the request coroutine, if it's still running
+    fn cancel_request(&self, key: u64) {
+        if let Some(r) = self.requests.lock().unwrap().get_mut(&key) {
+            r.cancel();
+        }
+    }
+}
+
+/// the result of a graceful shutdown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// requests that finished during the shutdown
+    pub drained: usize,
+    /// requests that were cancelled when the deadline expired
+    pub aborted: usize,
+}
+
+/// the counters of a running server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerMetrics {
+    /// requests whose service panicked
+    pub panics: u64,
+    /// connections rejected by the max connections and the max connections per ip
+    pub rejected_connections: u64,
+    /// connections closed for no frame arrived within the idle timeout
+    pub idle_timeouts: u64,
+    /// connections closed for a frame stalled longer than the read timeout
+    pub read_timeouts: u64,
+    /// connections closed for the responses not written within the write timeout
+    pub write_timeouts: u64,
+    /// connections closed for nothing received within the heartbeat timeout
+    pub heartbeat_timeouts: u64,
+}
+
 /// service instance
-pub struct ServerInstance(Option<coroutine::JoinHandle<()>>);
+pub struct ServerInstance {
+    handle: Option<coroutine::JoinHandle<()>>,
+    // the connection coroutines, which are cancelled when dropped
+    conns: Option<Arc<Manager>>,
+    state: Arc<ServerState>,
+}
 
 impl ServerInstance {
+    /// the number of the requests whose service panicked
+    pub fn panics(&self) -> u64 {
+        self.state.panics.load(Ordering::Relaxed)
+    }
+
+    /// the current counters of the server
+    pub fn metrics(&self) -> ServerMetrics {
+        let state = &self.state;
+        ServerMetrics {
+            panics: state.panics.load(Ordering::Relaxed),
+            rejected_connections: state.rejected_conns.load(Ordering::Relaxed),
+            idle_timeouts: state.idle_timeouts.load(Ordering::Relaxed),
+            read_timeouts: state.read_timeouts.load(Ordering::Relaxed),
+            write_timeouts: state.write_timeouts.load(Ordering::Relaxed),
+            heartbeat_timeouts: state.heartbeat_timeouts.load(Ordering::Relaxed),
+        }
+    }
+
     /// join the service, this would wait until the service is stopped
     pub fn join(mut self) -> std::thread::Result<()> {
-        if let Some(handle) = self.0.take() {
+        if let Some(handle) = self.handle.take() {
             handle.join()
         } else {
             Ok(())
         }
     }
+
+    /// gracefully shutdown the service
+    ///
+    /// stop accepting new connections and requests, tell the connected clients
+    /// to go away, and wait the running requests to finish and send out their
+    /// responses. the connections are still served in the meantime, new requests
+    /// are replied with `UNAVAILABLE` and the duplex streams keep receiving.
+    /// the requests that are still running at the deadline are cancelled,
+    /// and then the connections are closed
+    pub fn shutdown(mut self, deadline: Instant) -> ShutdownReport {
+        let state = self.state.clone();
+        let completed = state.completed.load(Ordering::Relaxed);
+        state.shutdown.store(true, Ordering::Release);
+
+        // tell the clients not to send new requests, the write blocks on a
+        // client that doesn't read, so each one is sent in its own coroutine
+        let conns: Vec<Conn> = state.conns.lock().unwrap().values().cloned().collect();
+        let go_away: Vec<_> = conns
+            .into_iter()
+            .filter_map(|conn| {
+                let writer = conn.writer.clone();
+                let builder = coroutine::Builder::new().name("GoAway".to_owned());
+                match go!(builder, move || writer(encode_control(0, Control::GoAway))) {
+                    Ok(h) => Some((conn, h)),
+                    Err(e) => {
+                        error!("server shutdown: spawn go away, err = {:?}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        // stop accepting, the connections are owned by the instance
+        if let Some(s) = self.handle.take() {
+            unsafe { s.coroutine().cancel() };
+            s.join().ok();
+        }
+
+        let pending = state.requests.lock().unwrap().len();
+        info!("server shutdown: wait {pending} running requests");
+        while !state.requests.lock().unwrap().is_empty() && Instant::now() < deadline {
+            coroutine::sleep(Duration::from_millis(10));
+        }
+
+        // cancel the stragglers, the ones writing their rsp are left to finish
+        let mut requests = state.requests.lock().unwrap();
+        let aborted = requests
+            .values_mut()
+            .map(Running::cancel)
+            .filter(|c| *c)
+            .count();
+        drop(requests);
+        if aborted > 0 {
+            warn!("server shutdown: cancel {aborted} running requests");
+        }
+
+        // close the connections, the go away still blocked is failed by it
+        for (conn, h) in go_away {
+            if !h.is_done() {
+                (conn.close)();
+            }
+            h.join().ok();
+        }
+        self.conns.take();
+
+        // the cancelled ones never return, only the finished ones are counted
+        let drained = state.completed.load(Ordering::Relaxed) - completed;
+        ShutdownReport {
+            drained: drained as usize,
+            aborted,
+        }
+    }
 }
 
 impl Drop for ServerInstance {
     fn drop(&mut self) {
-        if let Some(s) = self.0.take() {
+        if let Some(s) = self.handle.take() {
             unsafe { s.coroutine().cancel() };
             s.join().ok();
         }
+        self.conns.take();
+    }
+}
+
+/// run the service for the request frame and send out the response
+fn process<T: Server>(
+    server: &T,
+    request: &RequestGuard,
+    req: &Frame,
+    ctx: &RequestContext,
+    config: &ServerConfig,
+    writer: &FrameWriter,
+) {
+    let state = &request.state;
+    if ctx.is_expired() {
+        request.finish();
+        reply_expired(req.id, req.has_checksum(), writer);
+        return;
+    }
+
+    if req.is_stream() {
+        let mut rsp = RspSender::new(
+            req.id,
+            req.has_checksum(),
+            config.max_frame_len,
+            config.rsp_msg_len(ctx.features()),
+            request.writer(writer),
+        )
+        .with_deadline(ctx.deadline());
+        let service = |ctx: &RequestContext, req: &[u8], rsp: &mut RspSender| {
+            server.service_stream_with_context(ctx, req, rsp)
+        };
+        let ret = state.catch_panic(request.key, req.id, || {
+            run_stream_layers(config, ctx, req.decode_req(), &mut rsp, service)
+        });
+        request.finish();
+        rsp.finish(ret);
+        return;
+    }
+
+    let mut rsp = RspBuf::new();
+    rsp.set_checksum(req.has_checksum());
+    rsp.set_max_len(config.max_frame_len);
+    rsp.set_max_msg_len(config.rsp_msg_len(ctx.features()));
+    let service = |ctx: &RequestContext, req: &[u8], rsp: &mut RspBuf| {
+        server.service_with_context(ctx, req, rsp)
+    };
+    let ret = state.catch_panic(request.key, req.id, || {
+        config.layers.run(ctx, req.decode_req(), &mut rsp, &service)
+    });
+    request.finish();
+    let data = rsp.finish(req.id, ret);
+
+    info!("send rsp: id={}", req.id);
+    // send the result back to client
+    writer(data);
+}
+
+/// run the streaming service through the layers
+///
+/// `next.run` of the innermost layer runs the whole service, which sends the
+/// items by the `RspSender` directly. a rsp replied by a layer without calling
+/// `next` is sent as the only item
+fn run_stream_layers<F>(
+    config: &ServerConfig,
+    ctx: &RequestContext,
+    req: &[u8],
+    rsp: &mut RspSender,
+    service: F,
+) -> Result<(), WireError>
+where
+    F: FnMut(&RequestContext, &[u8], &mut RspSender) -> Result<(), WireError>,
+{
+    let service = RefCell::new(service);
+    let sender = RefCell::new(rsp);
+    let called = Cell::new(false);
+    let handler = |ctx: &RequestContext, req: &[u8], _rsp: &mut RspBuf| {
+        called.set(true);
+        (service.borrow_mut())(ctx, req, &mut sender.borrow_mut())
+    };
+    let mut buf = RspBuf::new();
+    match config.layers.run(ctx, req, &mut buf, &handler) {
+        Ok(()) if !called.get() => sender.into_inner().send(buf),
+        ret => ret,
+    }
+}
+
+/// reply the request that is expired before started
+fn reply_expired(id: u64, checksum: bool, writer: &FrameWriter) {
+    // the client would not wait for it any more
+    warn!("request deadline exceeded before started: id={}", id);
+    let mut rsp = RspBuf::new();
+    rsp.set_checksum(checksum);
+    let ret = Err(WireError::status(
+        StatusCode::DEADLINE_EXCEEDED,
+        "deadline exceeded",
+    ));
+    writer(rsp.finish(id, ret));
+}
+
+/// the rsp of a request rejected by the inflight limits
+fn reject_inflight(req: &Frame) -> Vec<u8> {
+    let mut rsp = RspBuf::new();
+    rsp.set_checksum(req.has_checksum());
+    let ret = Err(WireError::status(
+        StatusCode::RESOURCE_EXHAUSTED,
+        "too many inflight requests",
+    ));
+    rsp.finish(req.id, ret)
+}
+
+/// the rsp of a frame with a mismatched checksum
+fn reject_corrupted(id: u64) -> Vec<u8> {
+    let mut rsp = RspBuf::new();
+    rsp.set_checksum(true);
+    let ret = Err(WireError::status(
+        StatusCode::DATA_LOSS,
+        "frame checksum mismatch",
+    ));
+    rsp.finish(id, ret)
+}
+
+/// the rsp of a frame without a checksum, when the checksum is required
+fn reject_unchecked(req: &Frame) -> Vec<u8> {
+    let ret = Err(WireError::status(
+        StatusCode::INVALID_ARGUMENT,
+        "frame checksum required",
+    ));
+    RspBuf::new().finish(req.id, ret)
+}
+
+/// the rsp of a frame that uses the features not negotiated
+fn reject_features(req: &Frame, unsupported: Features) -> Vec<u8> {
+    let mut rsp = RspBuf::new();
+    rsp.set_checksum(req.has_checksum());
+    let ret = Err(WireError::status(
+        StatusCode::INVALID_ARGUMENT,
+        format!("features not negotiated: {unsupported:?}"),
+    ));
+    rsp.finish(req.id, ret)
+}
+
+/// cancel the running request of the connection, if any
+fn cancel_conn_request(state: &ServerState, running: &ConnRequests, id: u64) {
+    if let Some(request) = running.lock().unwrap().get(&id) {
+        request.cancelled.store(true, Ordering::Release);
+        if let Some(key) = request.key {
+            state.cancel_request(key);
+        }
+    }
+}
+
+/// run the duplex service for the streaming req and send out the responses
+fn process_duplex<T: Server>(
+    server: &T,
+    request: &RequestGuard,
+    ctx: &RequestContext,
+    checksum: bool,
+    config: &ServerConfig,
+    rx: mpsc::Receiver<Frame>,
+    writer: &FrameWriter,
+) {
+    let id = ctx.id();
+    if ctx.is_expired() {
+        request.finish();
+        reply_expired(id, checksum, writer);
+        return;
+    }
+
+    let mut reqs = ReqReceiver::new(rx);
+    let mut rsp = RspSender::new(
+        id,
+        checksum,
+        config.max_frame_len,
+        config.rsp_msg_len(ctx.features()),
+        request.writer(writer),
+    )
+    .with_deadline(ctx.deadline());
+    // the req items are received by the service, the layers see an empty req
+    let service = |ctx: &RequestContext, _req: &[u8], rsp: &mut RspSender| {
+        server.service_duplex_with_context(ctx, &mut reqs, rsp)
+    };
+    let ret = request.state.catch_panic(request.key, id, || {
+        run_stream_layers(config, ctx, &[], &mut rsp, service)
+    });
+    request.finish();
+    rsp.finish(ret);
+}
+
+/// set the listen backlog of a bound listener
+#[cfg(unix)]
+fn set_backlog<L: AsRawFd>(listener: &L, backlog: Option<i32>) -> io::Result<()> {
+    if let Some(backlog) = backlog {
+        // listen again on a listening socket would just update the backlog
+        if unsafe { libc::listen(listener.as_raw_fd(), backlog) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_backlog<L>(_listener: &L, backlog: Option<i32>) -> io::Result<()> {
+    if backlog.is_some() {
+        warn!("listen backlog is not supported on this platform");
+    }
+    Ok(())
+}
+
+/// wait for the next frame within the idle timeout
+/// return the reader of the frame, which applies the read timeout for the rest of it
+fn wait_frame<'a, S: StreamExt>(
+    rs: &'a mut BufReader<S>,
+    config: &ServerConfig,
+) -> io::Result<FrameReader<'a, S>> {
+    if config.idle_timeout.is_none() && config.read_timeout.is_none() {
+        return Ok(FrameReader { rs, deadline: None });
+    }
+
+    rs.get_mut().set_read_timeout(config.idle_timeout)?;
+    if rs.fill_buf()?.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if config.read_timeout.is_none() {
+        rs.get_mut().set_read_timeout(None)?;
+    }
+    Ok(FrameReader {
+        deadline: config.read_timeout.map(|t| Instant::now() + t),
+        rs,
+    })
+}
+
+/// read a frame before the deadline
+///
+/// the read timeout of the stream only bounds each read, so it's shrunk to
+/// the time left before each read, and a frame that trickles in is timed out
+struct FrameReader<'a, S: StreamExt> {
+    rs: &'a mut BufReader<S>,
+    deadline: Option<Instant>,
+}
+
+impl<S: StreamExt> Read for FrameReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            // only the reads on an empty buffer reach the stream
+            if self.rs.buffer().is_empty() {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                self.rs.get_mut().set_read_timeout(Some(remaining))?;
+            }
+        }
+        self.rs.read(buf)
     }
 }
 
+/// serve the requests from the stream until the connection is closed
+fn serve_conn<T: Server, S: StreamExt>(
+    server: Arc<T>,
+    config: Arc<ServerConfig>,
+    state: Arc<ServerState>,
+    mut stream: S,
+) {
+    let rs = match stream.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("server clone stream: err = {:?}", e);
+            return;
+        }
+    };
+    // used to close the connection when failed to write or the heartbeat timeout
+    let ctl = match stream.try_clone() {
+        Ok(s) => Arc::new(Mutex::new(s)),
+        Err(e) => {
+            error!("server clone stream: err = {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = stream.set_write_timeout(config.write_timeout) {
+        error!("server set write timeout: err = {:?}", e);
+        return;
+    }
+    // the read half of the stream
+    let mut rs = BufReader::new(rs);
+    // the client always sends the connection preface first
+    let features = match wait_frame(&mut rs, &config)
+        .map_err(Error::from)
+        .and_then(|mut r| server_handshake(&mut r, &mut stream, config.features))
+    {
+        Ok(f) => f,
+        Err(e) => {
+            error!("server handshake: err = {:?}, close connection", e);
+            return;
+        }
+    };
+    // the write half of the stream
+    let write_state = state.clone();
+    let write_ctl = ctl.clone();
+    let closed = AtomicBool::new(false);
+    let ws = Arc::new(QueuedWriter::with_error_handler(stream, move |e| {
+        // the frames after a failed write can't be decoded by the peer
+        if closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                write_state.write_timeouts.fetch_add(1, Ordering::Relaxed);
+                warn!("server write rsp: write timeout, close connection");
+            }
+            _ => error!("server write rsp: err = {:?}, close connection", e),
+        }
+        write_ctl.lock().unwrap().shutdown().ok();
+    }));
+    let writer: FrameWriter = Arc::new(move |data| ws.write(data));
+    let close_ctl = ctl.clone();
+    let close = Arc::new(move || {
+        close_ctl.lock().unwrap().shutdown().ok();
+    });
+    let liveness = Liveness::new();
+    let _heartbeat = match config.heartbeat {
+        Some((interval, timeout)) if features.contains(Features::HEARTBEAT) => {
+            let hb_state = state.clone();
+            let on_dead = move || {
+                hb_state.heartbeat_timeouts.fetch_add(1, Ordering::Relaxed);
+                warn!("server heartbeat timeout, close connection");
+                ctl.lock().unwrap().shutdown().ok();
+            };
+            match Heartbeat::spawn(interval, timeout, liveness.clone(), writer.clone(), on_dead) {
+                Ok(h) => Some(h),
+                Err(e) => {
+                    error!("server spawn heartbeat: err = {:?}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let inflight = config.max_inflight.map(Semaphore::new);
+    let conn = state.add_conn(Conn {
+        writer: writer.clone(),
+        close,
+    });
+    let peer = rs.get_ref().peer();
+    // the running duplex streams, the req items are routed by id
+    let mut duplex: HashMap<u64, mpsc::Sender<Frame>> = HashMap::new();
+    // the running requests that can be cancelled by the client
+    let running: ConnRequests = Arc::new(Mutex::new(HashMap::new()));
+
+    loop {
+        let mut r = match wait_frame(&mut rs, &config) {
+            Ok(r) => r,
+            Err(e) => {
+                match e.kind() {
+                    io::ErrorKind::UnexpectedEof => info!("server wait req: connection closed"),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                        state.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+                        info!("server wait req: idle timeout, close connection")
+                    }
+                    _ => error!("server wait req: err = {:?}", e),
+                }
+                break;
+            }
+        };
+
+        let req = match Frame::decode_from_with_limits(
+            &mut r,
+            config.max_frame_len,
+            config.max_msg_len,
+        ) {
+            Ok(r) => r,
+            Err(Error::Checksum { id, .. }) => {
+                // the corrupted frame is consumed, the id may be corrupted
+                // too, but the client drops the rsp of an unknown id
+                warn!("server decode req: corrupted frame, id={}", id);
+                if duplex.remove(&id).is_some() {
+                    cancel_conn_request(&state, &running, id);
+                }
+                writer(reject_corrupted(id));
+                continue;
+            }
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                info!("server decode req: connection closed");
+                break;
+            }
+            Err(Error::Io(ref e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                state.read_timeouts.fetch_add(1, Ordering::Relaxed);
+                warn!("server decode req: read timeout, close connection");
+                break;
+            }
+            Err(ref e @ (Error::FrameTooLarge { .. } | Error::MessageTooLarge { .. })) => {
+                error!("server decode req: {}, close connection", e);
+                break;
+            }
+            Err(ref e) => {
+                error!("server decode req: err = {:?}", e);
+                break;
+            }
+        };
+
+        liveness.touch();
+        info!("get request: id={:?}", req.id);
+        if req.is_control() {
+            match req.control() {
+                Some(Control::Cancel) if !features.contains(Features::CANCEL) => {
+                    warn!("server: ignore cancel not negotiated, id={:?}", req.id)
+                }
+                Some(Control::Ping) if !features.contains(Features::HEARTBEAT) => {
+                    warn!("server: ignore ping not negotiated, id={:?}", req.id)
+                }
+                Some(Control::Cancel) => {
+                    info!("cancel request: id={:?}", req.id);
+                    duplex.remove(&req.id);
+                    cancel_conn_request(&state, &running, req.id);
+                }
+                Some(Control::Ping) => writer(encode_control(req.id, Control::Pong)),
+                Some(Control::Pong) => {}
+                _ => warn!("server: unknown control frame, id={:?}", req.id),
+            }
+            continue;
+        }
+
+        let unsupported = req.features().difference(features);
+        if unsupported != Features::empty() {
+            warn!("server: reject frame of {:?}, id={}", unsupported, req.id);
+            if duplex.remove(&req.id).is_some() {
+                cancel_conn_request(&state, &running, req.id);
+            }
+            writer(reject_features(&req, unsupported));
+            continue;
+        }
+
+        if config.require_checksum && !req.has_checksum() {
+            warn!("server: reject frame without checksum, id={}", req.id);
+            if duplex.remove(&req.id).is_some() {
+                cancel_conn_request(&state, &running, req.id);
+            }
+            writer(reject_unchecked(&req));
+            continue;
+        }
+
+        if req.is_duplex() {
+            if let Some(tx) = duplex.get(&req.id) {
+                if req.is_end() {
+                    duplex.remove(&req.id);
+                } else {
+                    // the service may have returned, just drop the item
+                    tx.send(req).ok();
+                }
+                continue;
+            }
+        }
+
+        if state.is_shutdown() {
+            let mut rsp = RspBuf::new();
+            rsp.set_checksum(req.has_checksum());
+            let ret = Err(WireError::status(
+                StatusCode::UNAVAILABLE,
+                "server is shutting down",
+            ));
+            writer(rsp.finish(req.id, ret));
+            continue;
+        }
+
+        // with the backpressure policy this stops reading the connection
+        // until a running request is done. an open duplex stream keeps its
+        // permit until its req items are read, so then the permit is waited
+        // by the service coroutine instead, or the connection would deadlock
+        let permit = if duplex.is_empty() || state.inflight_policy == InflightPolicy::Reject {
+            // the client is not read when waiting, don't take it as dead
+            liveness.pause();
+            let permit = state.acquire_inflight(inflight.as_ref());
+            liveness.touch();
+            match permit {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("too many inflight requests, reject request: id={}", req.id);
+                    writer(reject_inflight(&req));
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let permit_state = state.clone();
+        let permit_limit = inflight.clone();
+        let wait_permit =
+            move || permit.or_else(|| permit_state.acquire_inflight(permit_limit.as_ref()));
+        let writer = writer.clone();
+        let server = server.clone();
+        let cfg = config.clone();
+        let id = req.id;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let request = ConnRequest {
+            key: None,
+            cancelled: cancelled.clone(),
+        };
+        running.lock().unwrap().insert(id, request);
+        let guard = ConnRequestGuard {
+            requests: running.clone(),
+            id,
+        };
+        let ctx = RequestContext::new(&req, Some(conn.key), peer.clone(), features, cancelled);
+        let key = if req.is_duplex() {
+            // a new duplex stream, spawn the service for it
+            // the context is made from the first frame of the stream
+            let checksum = req.has_checksum();
+            let (tx, rx) = mpsc::channel();
+            if !req.is_end() {
+                tx.send(req).ok();
+                duplex.insert(id, tx);
+            }
+            state.spawn_request(&config, move |request| {
+                let _guard = guard;
+                let permit = wait_permit();
+                process_duplex(&*server, request, &ctx, checksum, &cfg, rx, &writer);
+                drop(permit);
+            })
+        } else {
+            state.spawn_request(&config, move |request| {
+                let _guard = guard;
+                let permit = wait_permit();
+                process(&*server, request, &req, &ctx, &cfg, &writer);
+                drop(permit);
+            })
+        };
+        // record the request key, if it's still running
+        if let Some(request) = running.lock().unwrap().get_mut(&id) {
+            request.key = Some(key);
+        }
+    }
+}
+
+pub(crate) fn start_udp<T: Server, L: ToSocketAddrs>(
+    server: T,
+    config: ServerConfig,
+    addr: L,
+) -> io::Result<ServerInstance> {
+    let sock = UdpSocket::bind(addr)?; // the write half
+    let sock1 = sock.try_clone()?; // the read half
+    let state = ServerState::new(&config);
+    let server_state = state.clone();
+    let instance = go!(
+        coroutine::Builder::new().name("UdpServer".to_owned()),
+        move || {
+            let server = Arc::new(server);
+            let config = Arc::new(config);
+            // each udp packet can't exceed the max datagram size
+            let mut buf = vec![0u8; config.max_msg_len.min(UDP_MAX_LEN)];
+            let inflight = config.max_inflight.map(Semaphore::new);
+            // the write half need to be protected by mutex
+            // for that coroutine io obj can't shared safely
+            let sock = Arc::new(Mutex::new(sock));
+            loop {
+                // with the backpressure policy wait for the permit before reading the next packet
+                let permit = match state.inflight_policy {
+                    InflightPolicy::Backpressure => state.acquire_inflight(inflight.as_ref()),
+                    InflightPolicy::Reject => None,
+                };
+                let (len, addr) = t!(sock1.recv_from(&mut buf));
+                info!("recv_from: len={:?} addr={:?}", len, addr);
+
+                let sock = sock.clone();
+                let writer: FrameWriter = Arc::new(move |data: Vec<u8>| {
+                    info!("send_to: len={:?} addr={:?}", data.len(), addr);
+
+                    // send the result back to client
+                    // udp no need to protect by a mutex, each send would be one frame
+                    let s = sock.lock().unwrap();
+                    match s.send_to(&data, addr) {
+                        Ok(_) => {}
+                        Err(err) => error!("udp send_to failed, err={:?}", err),
+                    }
+                });
+                // if we failed to deserialize the request frame, just continue
+                let req = match Frame::decode_from_with_limits(
+                    &mut Cursor::new(&buf[..len]),
+                    config.max_frame_len,
+                    config.max_msg_len,
+                ) {
+                    Ok(req) => req,
+                    Err(Error::Checksum { id, .. }) => {
+                        warn!("udp server: corrupted frame, id={}", id);
+                        writer(reject_corrupted(id));
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("udp server decode req: err = {:?}", e);
+                        continue;
+                    }
+                };
+                if req.is_control() {
+                    // there is no connection state to cancel the request on udp
+                    warn!("udp server: ignore control frame, id={:?}", req.id);
+                    continue;
+                }
+                let unsupported = req.features().difference(config.features);
+                if unsupported != Features::empty() {
+                    warn!(
+                        "udp server: reject frame of {:?}, id={}",
+                        unsupported, req.id
+                    );
+                    writer(reject_features(&req, unsupported));
+                    continue;
+                }
+                if config.require_checksum && !req.has_checksum() {
+                    warn!("udp server: reject frame without checksum, id={}", req.id);
+                    writer(reject_unchecked(&req));
+                    continue;
+                }
+                if req.is_duplex() {
+                    // the req items may arrive out of order, not supported on udp
+                    let mut rsp = RspBuf::new();
+                    rsp.set_checksum(req.has_checksum());
+                    let ret = Err(WireError::status(
+                        StatusCode::UNIMPLEMENTED,
+                        "duplex stream is not supported on udp",
+                    ));
+                    writer(rsp.finish(req.id, ret));
+                    continue;
+                }
+                let permit = match permit.or_else(|| state.acquire_inflight(inflight.as_ref())) {
+                    Some(permit) => permit,
+                    None => {
+                        warn!("too many inflight requests, reject request: id={}", req.id);
+                        writer(reject_inflight(&req));
+                        continue;
+                    }
+                };
+                let server = server.clone();
+                let cfg = config.clone();
+                let cancelled = Arc::new(AtomicBool::new(false));
+                let ctx =
+                    RequestContext::new(&req, None, Peer::Inet(addr), config.features, cancelled);
+                state.spawn_request(&config, move |request| {
+                    process(&*server, request, &req, &ctx, &cfg, &writer);
+                    drop(permit);
+                });
+            }
+        }
+    )?;
+    Ok(ServerInstance {
+        handle: Some(instance),
+        conns: None,
+        state: server_state,
+    })
+}
+
+/// accept the connections and serve them until the listener is stopped
+fn accept_conns<T, S, I>(
+    name: &str,
+    server: T,
+    config: ServerConfig,
+    state: Arc<ServerState>,
+    manager: Arc<Manager>,
+    mut incoming: I,
+) where
+    T: Server,
+    S: StreamExt,
+    I: Iterator<Item = io::Result<S>>,
+{
+    let server = Arc::new(server);
+    let config = Arc::new(config);
+    let conns = config.max_connections.map(Semaphore::new);
+    let mut backoff = Duration::ZERO;
+    loop {
+        // with the wait policy stop accepting until a connection is closed
+        let permit = match config.connection_policy {
+            ConnectionPolicy::Wait => conns.as_ref().map(|s| s.acquire()),
+            ConnectionPolicy::Reject => None,
+        };
+        let stream = match incoming.next() {
+            Some(Ok(s)) => s,
+            Some(Err(e)) => {
+                // back off the errors like running out of file descriptors
+                backoff = (backoff * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX);
+                error!("{name} accept: err = {:?}, retry after {:?}", e, backoff);
+                coroutine::sleep(backoff);
+                continue;
+            }
+            None => break,
+        };
+        backoff = Duration::ZERO;
+        let permit = match permit {
+            Some(permit) => Some(permit),
+            None => match conns.as_ref().map(|s| s.try_acquire()) {
+                Some(None) => {
+                    state.rejected_conns.fetch_add(1, Ordering::Relaxed);
+                    warn!("{name} reach max connections, reject connection");
+                    continue;
+                }
+                Some(permit) => permit,
+                None => None,
+            },
+        };
+        let peer = match state.add_peer(stream.peer(), config.max_connections_per_ip) {
+            Some(peer) => peer,
+            None => {
+                state.rejected_conns.fetch_add(1, Ordering::Relaxed);
+                warn!("{name} reach max connections per ip, reject connection");
+                continue;
+            }
+        };
+        let server = server.clone();
+        let config = config.clone();
+        let state = state.clone();
+        manager.add(move |_| {
+            serve_conn(server, config, state, stream);
+            drop(peer);
+            drop(permit);
+        });
+    }
+}
+
+pub(crate) fn start_tcp<T: Server, L: ToSocketAddrs>(
+    server: T,
+    config: ServerConfig,
+    addr: L,
+) -> io::Result<ServerInstance> {
+    let listener = TcpListener::bind(addr)?;
+    set_backlog(&listener, config.backlog)?;
+    let state = ServerState::new(&config);
+    let server_state = state.clone();
+    let manager = Arc::new(Manager::new());
+    let conns = manager.clone();
+    let instance = go!(
+        coroutine::Builder::new().name("TcpServer".to_owned()),
+        move || accept_conns(
+            "tcp server",
+            server,
+            config,
+            state,
+            conns,
+            listener.incoming()
+        )
+    )?;
+    Ok(ServerInstance {
+        handle: Some(instance),
+        conns: Some(manager),
+        state: server_state,
+    })
+}
+
+#[cfg(unix)]
+pub(crate) fn start_uds<T: Server, P: AsRef<Path>>(
+    server: T,
+    config: ServerConfig,
+    path: P,
+) -> io::Result<ServerInstance> {
+    struct AutoDrop(UnixListener, PathBuf);
+    impl Drop for AutoDrop {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.1).ok();
+        }
+    }
+
+    std::fs::remove_file(&path).ok();
+    let listener = AutoDrop(UnixListener::bind(&path)?, path.as_ref().to_owned());
+    set_backlog(&listener.0, config.backlog)?;
+    let state = ServerState::new(&config);
+    let server_state = state.clone();
+    let manager = Arc::new(Manager::new());
+    let conns = manager.clone();
+    let instance = go!(
+        coroutine::Builder::new().name("Unix Socket Server".to_owned()),
+        move || {
+            let incoming = listener.0.incoming();
+            accept_conns("uds server", server, config, state, conns, incoming)
+        }
+    )?;
+    Ok(ServerInstance {
+        handle: Some(instance),
+        conns: Some(manager),
+        state: server_state,
+    })
+}
+
 /// Provides a function for starting the service.
 pub trait UdpServer: Server {
     /// Spawns the service, binding to the given address
     /// return a coroutine that you can cancel it when need to stop the service
     fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerInstance> {
-        let sock = UdpSocket::bind(addr)?; // the write half
-        let sock1 = sock.try_clone()?; // the read half
-        let instance = go!(
-            coroutine::Builder::new().name("UdpServer".to_owned()),
-            move || {
-                let server = Arc::new(self);
-                let mut buf = vec![0u8; 1024];
-                // the write half need to be protected by mutex
-                // for that coroutine io obj can't shared safely
-                let sock = Arc::new(Mutex::new(sock));
-                loop {
-                    // each udp packet should be less than 1024 bytes
-                    let (len, addr) = t!(sock1.recv_from(&mut buf));
-                    info!("recv_from: len={:?} addr={:?}", len, addr);
-
-                    // if we failed to deserialize the request frame, just continue
-                    let req = t!(Frame::decode_from(&mut Cursor::new(&buf)));
-                    let sock = sock.clone();
-                    let server = server.clone();
-                    // let mutex = mutex.clone();
-                    go!(move || {
-                        let mut rsp = RspBuf::new();
-                        let ret = server.service(req.decode_req(), &mut rsp);
-                        let data = rsp.finish(req.id, ret);
-
-                        info!("send_to: len={:?} addr={:?}", data.len(), addr);
-
-                        // send the result back to client
-                        // udp no need to protect by a mutex, each send would be one frame
-                        let s = sock.lock().unwrap();
-                        match s.send_to(&data, addr) {
-                            Ok(_) => {}
-                            Err(err) => error!("udp send_to failed, err={:?}", err),
-                        }
-                    });
-                }
-            }
-        )?;
-        Ok(ServerInstance(Some(instance)))
+        ServerBuilder::new(self).start_udp(addr)
     }
 }
 
@@ -90,53 +1321,19 @@ pub trait TcpServer: Server {
     /// Spawns the service, binding to the given address
     /// return a coroutine that you can cancel it when need to stop the service
     fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerInstance> {
-        let listener = TcpListener::bind(addr)?;
-        let instance = go!(
-            coroutine::Builder::new().name("TcpServer".to_owned()),
-            move || {
-                let server = Arc::new(self);
-                let manager = Manager::new();
-                for stream in listener.incoming() {
-                    let stream = t!(stream);
-                    let server = server.clone();
-                    manager.add(move |_| {
-                        let rs = stream.try_clone().expect("failed to clone stream");
-                        // the read half of the stream
-                        let mut rs = BufReader::new(rs);
-                        // the write half of the stream
-                        let ws = Arc::new(QueuedWriter::new(stream));
-
-                        loop {
-                            let req = match Frame::decode_from(&mut rs) {
-                                Ok(r) => r,
-                                Err(ref e) => {
-                                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                                        info!("tcp server decode req: connection closed");
-                                    } else {
-                                        error!("tcp server decode req: err = {:?}", e);
-                                    }
-                                    break;
-                                }
-                            };
-
-                            info!("get request: id={:?}", req.id);
-                            let w_stream = ws.clone();
-                            let server = server.clone();
-                            go!(move || {
-                                let mut rsp = RspBuf::new();
-                                let ret = server.service(req.decode_req(), &mut rsp);
-                                let data = rsp.finish(req.id, ret);
-
-                                info!("send rsp: id={}", req.id);
-                                // send the result back to client
-                                w_stream.write(data);
-                            });
-                        }
-                    });
-                }
-            }
-        )?;
-        Ok(ServerInstance(Some(instance)))
+        ServerBuilder::new(self).start_tcp(addr)
+    }
+
+    /// Spawns the service with the max frame len, binding to the given address
+    /// connections that send a frame longer than `max_frame_len` would be closed
+    fn start_with_limit<L: ToSocketAddrs>(
+        self,
+        addr: L,
+        max_frame_len: usize,
+    ) -> io::Result<ServerInstance> {
+        ServerBuilder::new(self)
+            .max_frame_len(max_frame_len)
+            .start_tcp(addr)
     }
 }
 
@@ -146,62 +1343,19 @@ pub trait UdsServer: Server {
     /// Spawns the service, binding to the given address
     /// return a coroutine that you can cancel it when need to stop the service
     fn start<P: AsRef<Path>>(self, path: P) -> io::Result<ServerInstance> {
-        struct AutoDrop(UnixListener, PathBuf);
-        impl Drop for AutoDrop {
-            fn drop(&mut self) {
-                std::fs::remove_file(&self.1).ok();
-            }
-        }
-
-        std::fs::remove_file(&path).ok();
-        let listener = AutoDrop(UnixListener::bind(&path)?, path.as_ref().to_owned());
-        let instance = go!(
-            coroutine::Builder::new().name("Unix Socket Server".to_owned()),
-            move || {
-                let server = Arc::new(self);
-                let manager = Manager::new();
-                for stream in listener.0.incoming() {
-                    let stream = t!(stream);
-                    let server = server.clone();
-                    manager.add(move |_| {
-                        let rs = stream.try_clone().expect("failed to clone stream");
-                        // the read half of the stream
-                        let mut rs = BufReader::new(rs);
-                        // the write half need to be protected by mutex
-                        // for that coroutine io obj can't shared safely
-                        let ws = Arc::new(QueuedWriter::new(stream));
-
-                        loop {
-                            let req = match Frame::decode_from(&mut rs) {
-                                Ok(r) => r,
-                                Err(ref e) => {
-                                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                                        info!("uds server decode req: connection closed");
-                                    } else {
-                                        error!("uds server decode req: err = {:?}", e);
-                                    }
-                                    break;
-                                }
-                            };
-
-                            info!("get request: id={:?}", req.id);
-                            let w_stream = ws.clone();
-                            let server = server.clone();
-                            go!(move || {
-                                let mut rsp = RspBuf::new();
-                                let ret = server.service(req.decode_req(), &mut rsp);
-                                let data = rsp.finish(req.id, ret);
-
-                                info!("send rsp: id={}", req.id);
-                                // send the result back to client
-                                w_stream.write(data);
-                            });
-                        }
-                    });
-                }
-            }
-        )?;
-        Ok(ServerInstance(Some(instance)))
+        ServerBuilder::new(self).start_uds(path)
+    }
+
+    /// Spawns the service with the max frame len, binding to the given address
+    /// connections that send a frame longer than `max_frame_len` would be closed
+    fn start_with_limit<P: AsRef<Path>>(
+        self,
+        path: P,
+        max_frame_len: usize,
+    ) -> io::Result<ServerInstance> {
+        ServerBuilder::new(self)
+            .max_frame_len(max_frame_len)
+            .start_uds(path)
     }
 }
 
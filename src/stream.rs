@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::{Error, StatusCode, WireError};
+use crate::frame::{
+    encode_control, Control, Frame, ReqBuf, RspBuf, RSP_STREAM_END, RSP_STREAM_ITEM,
+};
+use crate::queued_writer::FrameWriter;
+
+use may::sync::{mpsc, Mutex};
+
+/// the id of a streaming req always has the highest bit set
+/// so that it would never conflict with the waiter ids
+pub(crate) const STREAM_ID_BIT: u64 = 1 << 63;
+
+// the receiving half of each stream, indexed by the stream id
+pub(crate) type StreamMap = Mutex<HashMap<u64, mpsc::Sender<Result<Frame, Error>>>>;
+
+/// send the items of a streaming req, returned by `MultiplexClient::open_stream`
+///
+/// the req stream is half-closed by `finish` or when the sender is dropped
+pub struct ReqSender {
+    id: u64,
+    checksum: bool,
+    max_len: usize,
+    max_msg_len: usize,
+    writer: FrameWriter,
+    closed: bool,
+}
+
+impl ReqSender {
+    pub(crate) fn new(
+        id: u64,
+        checksum: bool,
+        max_len: usize,
+        max_msg_len: usize,
+        writer: FrameWriter,
+    ) -> Self {
+        ReqSender {
+            id,
+            checksum,
+            max_len,
+            max_msg_len,
+            writer,
+            closed: false,
+        }
+    }
+
+    /// send one item of the streaming req to the server
+    /// return `Error::MessageTooLarge` if the item exceeds the max message len
+    pub fn send(&mut self, mut req: ReqBuf) -> Result<(), Error> {
+        req.set_checksum(self.checksum);
+        req.set_max_len(self.max_len);
+        req.set_max_msg_len(self.max_msg_len);
+        req.set_duplex(false);
+        let buf = req.finish(self.id)?;
+        info!("send req item: id={}", self.id);
+        (self.writer)(buf);
+        Ok(())
+    }
+
+    /// half-close the req stream, the rsp stream is still alive
+    pub fn finish(mut self) {
+        self.close();
+    }
+
+    fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        let mut req = ReqBuf::new();
+        req.set_checksum(self.checksum);
+        req.set_duplex(true);
+        info!("send req end: id={}", self.id);
+        // an empty frame would never exceed the limit
+        if let Ok(buf) = req.finish(self.id) {
+            (self.writer)(buf);
+        }
+    }
+}
+
+impl Drop for ReqSender {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// receive the items of a streaming req, used by `Server::service_duplex`
+///
+/// each item is a req frame, the iteration stops when the client half-closes
+/// the stream or the connection is closed
+pub struct ReqReceiver {
+    rx: mpsc::Receiver<Frame>,
+}
+
+impl ReqReceiver {
+    pub(crate) fn new(rx: mpsc::Receiver<Frame>) -> Self {
+        ReqReceiver { rx }
+    }
+}
+
+impl Iterator for ReqReceiver {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+/// send the items of a streaming rsp, used by `Server::service_stream`
+pub struct RspSender {
+    id: u64,
+    checksum: bool,
+    max_len: usize,
+    max_msg_len: usize,
+    // the deadline of the request, no more items after it
+    deadline: Option<Instant>,
+    writer: FrameWriter,
+}
+
+impl RspSender {
+    pub(crate) fn new(
+        id: u64,
+        checksum: bool,
+        max_len: usize,
+        max_msg_len: usize,
+        writer: FrameWriter,
+    ) -> Self {
+        RspSender {
+            id,
+            checksum,
+            max_len,
+            max_msg_len,
+            deadline: None,
+            writer,
+        }
+    }
+
+    pub(crate) fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// send one item of the streaming rsp to the client
+    /// return `ServerSerialize` error if the item exceeds the max message len,
+    /// or a `DEADLINE_EXCEEDED` status once the deadline of the request is expired
+    pub fn send(&mut self, mut rsp: RspBuf) -> Result<(), WireError> {
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            warn!("stream deadline exceeded: id={}", self.id);
+            return Err(WireError::status(
+                StatusCode::DEADLINE_EXCEEDED,
+                "deadline exceeded",
+            ));
+        }
+
+        let len = rsp.frame_len();
+        if len > self.max_msg_len {
+            let s = format!(
+                "encode too big rsp message length. len={len}, max={}",
+                self.max_msg_len
+            );
+            error!("{s}");
+            return Err(WireError::ServerSerialize(s));
+        }
+
+        rsp.set_checksum(self.checksum);
+        rsp.set_max_len(self.max_len);
+        rsp.set_max_msg_len(self.max_msg_len);
+        info!("send rsp item: id={}", self.id);
+        (self.writer)(rsp.finish_as(self.id, RSP_STREAM_ITEM, Ok(())));
+        Ok(())
+    }
+
+    /// end the stream, an error would be sent as the last rsp
+    pub(crate) fn finish(self, ret: Result<(), WireError>) {
+        let mut rsp = RspBuf::new();
+        rsp.set_checksum(self.checksum);
+        rsp.set_max_len(self.max_len);
+        rsp.set_max_msg_len(self.max_msg_len);
+        info!("send rsp end: id={}", self.id);
+        (self.writer)(rsp.finish_as(self.id, RSP_STREAM_END, ret));
+    }
+}
+
+/// receive the items of a streaming rsp
+///
+/// each item is a rsp frame, you should parsing it into the final response.
+/// the iteration stops after the last item or the first error. the server
+/// abandons the stream if the receiver is dropped before the last item
+pub struct RspReceiver {
+    id: u64,
+    streams: Arc<StreamMap>,
+    rx: mpsc::Receiver<Result<Frame, Error>>,
+    timeout: Option<Duration>,
+    // used to cancel the stream when dropped
+    writer: FrameWriter,
+    done: bool,
+    // the server has ended the stream
+    ended: bool,
+}
+
+impl RspReceiver {
+    pub(crate) fn new(
+        id: u64,
+        streams: Arc<StreamMap>,
+        rx: mpsc::Receiver<Result<Frame, Error>>,
+        timeout: Option<Duration>,
+        writer: FrameWriter,
+    ) -> Self {
+        RspReceiver {
+            id,
+            streams,
+            rx,
+            timeout,
+            writer,
+            done: false,
+            ended: false,
+        }
+    }
+
+    /// the id of the stream
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// whether the stream is registered in the streams of a client
+    pub(crate) fn belongs_to(&self, streams: &Arc<StreamMap>) -> bool {
+        Arc::ptr_eq(&self.streams, streams)
+    }
+
+    /// receive the next rsp frame, the timeout is applied for each item
+    fn recv(&self) -> Result<Frame, Error> {
+        let closed = || {
+            let e = io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed");
+            Error::Io(e)
+        };
+        match self.timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => Error::Timeout,
+                mpsc::RecvTimeoutError::Disconnected => closed(),
+            })?,
+            None => self.rx.recv().map_err(|_| closed())?,
+        }
+    }
+}
+
+impl Iterator for RspReceiver {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let frame = match self.recv() {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        let ty = frame.rsp_type();
+        if ty == Some(RSP_STREAM_ITEM) {
+            return Some(Ok(frame));
+        }
+
+        self.done = true;
+        self.ended = true;
+        if ty == Some(RSP_STREAM_END) {
+            return None;
+        }
+        // an error rsp, or a single rsp from a server that doesn't stream
+        match frame.decode_rsp() {
+            Ok(_) => Some(Ok(frame)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Drop for RspReceiver {
+    fn drop(&mut self) {
+        // a stream that is failed or cancelled is already unregistered
+        let registered = self.streams.lock().unwrap().remove(&self.id).is_some();
+        if registered && !self.ended {
+            info!("rsp receiver dropped, cancel stream: id={}", self.id);
+            (self.writer)(encode_control(self.id, Control::Cancel));
+        }
+    }
+}
@@ -3,11 +3,20 @@ use std::time::Duration;
 
 use crate::errors::Error;
 use crate::frame::{Frame, ReqBuf};
+use crate::handshake::{read_server_preface, write_preface, Features};
 use crate::stream_ext::StreamExt;
 
 pub struct StreamClient<S: StreamExt> {
     // each request would have a unique id
     id: u64,
+    // append checksum to the request frames
+    checksum: bool,
+    // the timeout that is sent to the server
+    timeout: Option<Duration>,
+    // the features offered to the server
+    offer: Features,
+    // the features negotiated with the server, set after the handshake
+    features: Option<Features>,
     // the connection
     stream: BufReader<S>,
 }
@@ -17,6 +26,10 @@ impl<S: StreamExt> StreamClient<S> {
     pub fn new(stream: S) -> Self {
         StreamClient {
             id: 0,
+            checksum: false,
+            timeout: None,
+            offer: Features::all(),
+            features: None,
             stream: BufReader::with_capacity(1024, stream),
         }
     }
@@ -25,30 +38,69 @@ impl<S: StreamExt> StreamClient<S> {
 impl<S: StreamExt> StreamClient<S> {
     /// set timeout
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), io::Error> {
-        self.stream.get_mut().set_read_timeout(timeout)
+        self.stream.get_mut().set_read_timeout(Some(timeout))?;
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+
+    /// append a crc32c checksum to each request frame
+    /// the server would reply with checksummed frames as well.
+    /// it's left out when the `CHECKSUM` feature is not negotiated
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    /// offer only the features to the server, this must be set before the handshake
+    /// the parts of the requests that are not negotiated are left out
+    pub fn set_features(&mut self, features: Features) {
+        self.offer = features;
     }
 }
 
 impl<S: StreamExt> StreamClient<S> {
+    /// exchange the connection preface with the server if not done yet,
+    /// this is done by the first call automatically.
+    /// return the negotiated features, or `Error::VersionMismatch` if the
+    /// server speaks a different protocol version
+    pub fn handshake(&mut self) -> Result<Features, Error> {
+        if let Some(features) = self.features {
+            return Ok(features);
+        }
+        // the pings can't be replied between the calls
+        let features = self.offer.difference(Features::HEARTBEAT);
+        write_preface(self.stream.get_mut(), features)?;
+        let features = read_server_preface(&mut self.stream)?;
+        self.features = Some(features);
+        Ok(features)
+    }
+
     /// call the server
     /// the request must be encoded into the ReqBuf
     /// the response is the raw frame, you should parsing it into final response
-    pub fn call_service(&mut self, req: ReqBuf) -> Result<Frame, Error> {
+    pub fn call_service(&mut self, mut req: ReqBuf) -> Result<Frame, Error> {
+        let features = self.handshake()?;
         let id = self.id;
         self.id += 1;
         info!("request id = {}", id);
 
         // encode the request
-        self.stream.get_mut().write_all(&(req.finish(id)))?;
+        req.set_checksum(self.checksum && features.contains(Features::CHECKSUM));
+        let timeout = req.timeout().or(self.timeout);
+        req.set_timeout(timeout.filter(|_| features.contains(Features::DEADLINE)));
+        self.stream.get_mut().write_all(&(req.finish(id)?))?;
 
         // read the response
         loop {
             // deserialize the rsp
-            let rsp_frame = Frame::decode_from(&mut self.stream)
-                .map_err(|e| Error::ClientDeserialize(e.to_string()))?;
+            let rsp_frame = match Frame::decode_from(&mut self.stream) {
+                Ok(r) => r,
+                Err(Error::Checksum { id: rsp_id, .. }) if rsp_id != id => continue,
+                Err(Error::Io(e)) => return Err(Error::ClientDeserialize(e.to_string())),
+                Err(e) => return Err(e),
+            };
 
             // discard the rsp that is is not belong to us
-            if rsp_frame.id == id {
+            if rsp_frame.id == id && !rsp_frame.is_control() {
                 info!("get response id = {}", id);
                 return Ok(rsp_frame);
             }
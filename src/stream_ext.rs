@@ -1,26 +1,103 @@
 use std::io::{self, Read, Write};
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 
+use crate::context::Peer;
+#[cfg(unix)]
+use crate::context::PeerCred;
+
 use may::io::SplitIo;
 
 pub trait StreamExt: Sized + SplitIo + Read + Write + Send + 'static {
     fn try_clone(&self) -> io::Result<Self>;
-    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+    /// shutdown both the read and write halves of the stream
+    fn shutdown(&self) -> io::Result<()>;
+    /// the peer of the stream, used for the request context
+    fn peer(&self) -> Peer {
+        Peer::Unknown
+    }
 }
 
 macro_rules! impl_stream_ext {
-    ($name: ty) => {
+    ($name: ty, $peer: path) => {
         impl StreamExt for $name {
             fn try_clone(&self) -> io::Result<Self> {
                 (*self).try_clone()
             }
-            fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
-                (*self).set_read_timeout(Some(timeout))
+            fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+                (*self).set_read_timeout(timeout)
+            }
+            fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+                (*self).set_write_timeout(timeout)
+            }
+            fn shutdown(&self) -> io::Result<()> {
+                (*self).shutdown(Shutdown::Both)
+            }
+            fn peer(&self) -> Peer {
+                match $peer(self) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        warn!("get stream peer: err = {:?}", e);
+                        Peer::Unknown
+                    }
+                }
             }
         }
     };
 }
 
-impl_stream_ext!(may::net::TcpStream);
+/// get the credentials of the unix domain socket peer
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_cred<S: AsRawFd>(s: &S) -> io::Result<PeerCred> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            s.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: Some(cred.pid),
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/// get the credentials of the unix domain socket peer
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn peer_cred<S: AsRawFd>(s: &S) -> io::Result<PeerCred> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(s.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: None,
+        uid,
+        gid,
+    })
+}
+
+fn tcp_peer(s: &may::net::TcpStream) -> io::Result<Peer> {
+    s.peer_addr().map(Peer::Inet)
+}
+
+#[cfg(unix)]
+fn uds_peer(s: &may::os::unix::net::UnixStream) -> io::Result<Peer> {
+    peer_cred(s).map(Peer::Unix)
+}
+
+impl_stream_ext!(may::net::TcpStream, tcp_peer);
 #[cfg(unix)]
-impl_stream_ext!(may::os::unix::net::UnixStream);
+impl_stream_ext!(may::os::unix::net::UnixStream, uds_peer);
@@ -3,7 +3,7 @@ use std::net::ToSocketAddrs;
 use std::time::Duration;
 
 use crate::errors::Error;
-use crate::frame::{Frame, ReqBuf};
+use crate::frame::{Frame, ReqBuf, UDP_MAX_LEN};
 
 use may::net::UdpSocket;
 
@@ -11,6 +11,10 @@ use may::net::UdpSocket;
 pub struct UdpClient {
     // each request would have a unique id
     id: u64,
+    // append checksum to the request frames
+    checksum: bool,
+    // the timeout that is sent to the server
+    timeout: Duration,
     // the connection
     sock: UdpSocket,
     // send/recv buf
@@ -23,12 +27,15 @@ impl UdpClient {
         // this would bind a random port by the system
         let sock = UdpSocket::bind("0.0.0.0:0")?;
         sock.connect(addr)?;
-        sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let timeout = Duration::from_secs(1);
+        sock.set_read_timeout(Some(timeout)).unwrap();
 
         Ok(UdpClient {
             sock,
             id: 0,
-            buf: vec![0; 1024],
+            checksum: false,
+            timeout,
+            buf: vec![0; UDP_MAX_LEN],
         })
     }
 
@@ -36,6 +43,13 @@ impl UdpClient {
     /// the initial timeout is 1 seconds
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.sock.set_read_timeout(Some(timeout)).unwrap();
+        self.timeout = timeout;
+    }
+
+    /// append a crc32c checksum to each request frame
+    /// the server would reply with checksummed frames as well
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
     }
 }
 
@@ -43,24 +57,32 @@ impl UdpClient {
     /// call the server
     /// the request must be encoded into the ReqBuf
     /// the response is the raw frame, you should parsing it into final response
-    pub fn call_service(&mut self, req: ReqBuf) -> Result<Frame, Error> {
+    pub fn call_service(&mut self, mut req: ReqBuf) -> Result<Frame, Error> {
         let id = self.id;
         self.id += 1;
         info!("request id = {}", id);
 
         // send the data to server
-        self.sock.send(&(req.finish(id))).map_err(Error::from)?;
+        req.set_checksum(self.checksum);
+        if req.timeout().is_none() {
+            req.set_timeout(Some(self.timeout));
+        }
+        self.sock.send(&(req.finish(id)?)).map_err(Error::from)?;
 
         // read the response
         loop {
-            self.sock.recv(&mut self.buf).map_err(Error::from)?;
+            let len = self.sock.recv(&mut self.buf).map_err(Error::from)?;
 
             // deserialize the rsp
-            let rsp_frame = Frame::decode_from(&mut Cursor::new(&self.buf))
-                .map_err(|e| Error::ClientDeserialize(e.to_string()))?;
+            let rsp_frame = match Frame::decode_from(&mut Cursor::new(&self.buf[..len])) {
+                Ok(r) => r,
+                Err(Error::Checksum { id: rsp_id, .. }) if rsp_id != id => continue,
+                Err(Error::Io(e)) => return Err(Error::ClientDeserialize(e.to_string())),
+                Err(e) => return Err(e),
+            };
 
             // discard the rsp that is is not belong to us
-            if rsp_frame.id == id {
+            if rsp_frame.id == id && !rsp_frame.is_control() {
                 info!("get response id = {}", id);
                 return Ok(rsp_frame);
             }
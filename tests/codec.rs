@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use conetty::{ClientExt, MultiplexClient, Raw, TcpServer, TypedServer};
+
+fn connect(port: u16) -> MultiplexClient<may::net::TcpStream> {
+    let tcp_stream = may::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    client
+}
+
+#[test]
+fn raw_codec() {
+    let server = TypedServer::new(Raw, |mut req: Vec<u8>| {
+        req.reverse();
+        Ok(req)
+    });
+    let _server = server.start(("127.0.0.1", 2017)).unwrap();
+
+    let client = connect(2017);
+    let rsp: Vec<u8> = client.call_with(Raw, &vec![1, 2, 3]).unwrap();
+    assert_eq!(rsp, [3, 2, 1]);
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn bincode_codec() {
+    use conetty::{Bincode, Error, StatusCode, WireError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Add {
+        x: u32,
+        y: u32,
+    }
+
+    let server = TypedServer::new(Bincode, |req: Add| {
+        req.x
+            .checked_add(req.y)
+            .ok_or_else(|| WireError::status(StatusCode::INVALID_ARGUMENT, "overflow"))
+    });
+    let _server = server.start(("127.0.0.1", 2018)).unwrap();
+
+    let client = connect(2018);
+    let rsp: u32 = client.call(&Add { x: 1, y: 2 }).unwrap();
+    assert_eq!(rsp, 3);
+
+    let rsp: Result<u32, _> = client.call(&Add { x: u32::MAX, y: 1 });
+    assert!(matches!(rsp, Err(Error::Status { .. })));
+
+    // the req can't be decoded by the server
+    let rsp: Result<u32, _> = client.call(&1u8);
+    assert!(matches!(rsp, Err(Error::ServerDeserialize(_))));
+
+    // the rsp can't be decoded by the client
+    let rsp: Result<String, _> = client.call(&Add { x: 1, y: 2 });
+    assert!(matches!(rsp, Err(Error::ClientDeserialize(_))));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_codec() {
+    use conetty::{Error, Json};
+    use std::collections::HashMap;
+
+    let server = TypedServer::new(Json, |req: HashMap<String, u32>| {
+        Ok(req.values().sum::<u32>())
+    });
+    let _server = server.start(("127.0.0.1", 2019)).unwrap();
+
+    let client = connect(2019);
+    let req = HashMap::from([("a".to_owned(), 1), ("b".to_owned(), 2)]);
+    let rsp: u32 = client.call_with(Json, &req).unwrap();
+    assert_eq!(rsp, 3);
+
+    let rsp: Result<u32, _> = client.call_with(Json, &"not a map".to_owned());
+    assert!(matches!(rsp, Err(Error::ServerDeserialize(_))));
+}
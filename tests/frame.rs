@@ -0,0 +1,292 @@
+use std::io::{Cursor, Write};
+
+use conetty::{Error, Frame, ReqBuf, RspBuf};
+
+#[test]
+fn checksum_roundtrip() {
+    let mut req = ReqBuf::new();
+    req.set_checksum(true);
+    req.write_all(b"hello").unwrap();
+    let data = req.finish(7).unwrap();
+
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert_eq!(frame.id, 7);
+    assert!(frame.has_checksum());
+    assert_eq!(frame.decode_req(), b"hello");
+
+    let mut rsp = RspBuf::new();
+    rsp.set_checksum(true);
+    rsp.write_all(b"world").unwrap();
+    let data = rsp.finish(7, Ok(()));
+
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert!(frame.has_checksum());
+    assert_eq!(frame.decode_rsp().unwrap(), b"world");
+}
+
+#[test]
+fn checksum_mismatch() {
+    let mut req = ReqBuf::new();
+    req.set_checksum(true);
+    req.write_all(b"hello").unwrap();
+    let mut data = req.finish(7).unwrap();
+    // corrupt the payload
+    data[16] ^= 0xff;
+
+    match Frame::decode_from(&mut Cursor::new(data)) {
+        Err(Error::Checksum { id, .. }) => assert_eq!(id, 7),
+        ret => panic!("unexpected decode result: {ret:?}"),
+    }
+}
+
+#[test]
+fn req_too_large() {
+    let mut req = ReqBuf::new();
+    req.set_max_msg_len(32);
+    req.write_all(&[0u8; 32]).unwrap();
+    match req.finish(1) {
+        Err(Error::MessageTooLarge { len, max }) => {
+            assert_eq!(len, 48);
+            assert_eq!(max, 32);
+        }
+        ret => panic!("unexpected encode result: {ret:?}"),
+    }
+}
+
+#[test]
+fn rsp_too_large() {
+    let mut rsp = RspBuf::new();
+    rsp.set_max_msg_len(32);
+    rsp.write_all(&[0u8; 32]).unwrap();
+    let data = rsp.finish(1, Ok(()));
+
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    match frame.decode_rsp() {
+        Err(Error::ServerSerialize(_)) => {}
+        ret => panic!("unexpected rsp: {ret:?}"),
+    }
+}
+
+#[test]
+fn decode_too_large() {
+    let mut req = ReqBuf::new();
+    req.write_all(&[0u8; 32]).unwrap();
+    let data = req.finish(1).unwrap();
+
+    match Frame::decode_from_with_limit(&mut Cursor::new(data), 32) {
+        Err(Error::FrameTooLarge { len, max }) => {
+            assert_eq!(len, 48);
+            assert_eq!(max, 32);
+        }
+        ret => panic!("unexpected decode result: {ret:?}"),
+    }
+}
+
+#[test]
+fn chunk_roundtrip() {
+    let payload: Vec<u8> = (0..100).collect();
+    let mut req = ReqBuf::new();
+    req.set_checksum(true);
+    req.set_max_len(32);
+    req.write_all(&payload).unwrap();
+    let data = req.finish(3).unwrap();
+    // 7 continuation frames, each has a 16 bytes head and a 4 bytes checksum
+    assert_eq!(data.len(), 100 + 7 * 20);
+
+    let mut r = Cursor::new(data);
+    let frame = Frame::decode_from_with_limit(&mut r, 32).unwrap();
+    assert_eq!(frame.id, 3);
+    assert!(frame.has_checksum());
+    assert_eq!(frame.decode_req(), payload.as_slice());
+
+    let mut rsp = RspBuf::new();
+    rsp.set_max_len(32);
+    rsp.write_all(&payload).unwrap();
+    let data = rsp.finish(3, Ok(()));
+
+    let frame = Frame::decode_from_with_limit(&mut Cursor::new(data), 32).unwrap();
+    assert_eq!(frame.decode_rsp().unwrap(), payload.as_slice());
+}
+
+#[test]
+fn chunk_checksum_mismatch() {
+    let mut req = ReqBuf::new();
+    req.set_checksum(true);
+    req.set_max_len(32);
+    req.write_all(&[0u8; 40]).unwrap();
+    let mut data = req.finish(3).unwrap();
+    // corrupt the first continuation frame
+    data[16] ^= 0xff;
+
+    let mut req = ReqBuf::new();
+    req.write_all(b"next").unwrap();
+    data.extend(req.finish(4).unwrap());
+
+    // the whole message is consumed before reporting the mismatch
+    let mut r = Cursor::new(data);
+    match Frame::decode_from_with_limit(&mut r, 32) {
+        Err(Error::Checksum { id, .. }) => assert_eq!(id, 3),
+        ret => panic!("unexpected decode result: {ret:?}"),
+    }
+    let frame = Frame::decode_from_with_limit(&mut r, 32).unwrap();
+    assert_eq!(frame.id, 4);
+    assert_eq!(frame.decode_req(), b"next");
+}
+
+#[test]
+fn decode_message_too_large() {
+    let mut req = ReqBuf::new();
+    req.set_max_len(32);
+    req.write_all(&[0u8; 100]).unwrap();
+    let data = req.finish(1).unwrap();
+
+    match Frame::decode_from_with_limits(&mut Cursor::new(data), 32, 64) {
+        Err(Error::MessageTooLarge { max, .. }) => assert_eq!(max, 64),
+        ret => panic!("unexpected decode result: {ret:?}"),
+    }
+
+    // the frame that is not chunked is limited as well
+    let mut req = ReqBuf::new();
+    req.write_all(&[0u8; 100]).unwrap();
+    let data = req.finish(2).unwrap();
+    match Frame::decode_from_with_limits(&mut Cursor::new(data), 1024, 64) {
+        Err(Error::MessageTooLarge { len, max }) => assert_eq!((len, max), (100, 64)),
+        ret => panic!("unexpected decode result: {ret:?}"),
+    }
+}
+
+#[test]
+fn deadline_roundtrip() {
+    use std::time::{Duration, Instant};
+
+    let mut req = ReqBuf::new();
+    req.set_timeout(Some(Duration::from_secs(1)));
+    req.write_all(b"hello").unwrap();
+    let data = req.finish(5).unwrap();
+
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert_eq!(frame.decode_req(), b"hello");
+    let deadline = frame.deadline().unwrap();
+    assert!(deadline > Instant::now() + Duration::from_millis(500));
+
+    // no deadline by default
+    let data = ReqBuf::new().finish(6).unwrap();
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert!(frame.deadline().is_none());
+
+    // a timeout too large for a deadline doesn't fail the decoding
+    let mut req = ReqBuf::new();
+    req.set_timeout(Some(Duration::from_secs(1)));
+    let mut data = req.finish(7).unwrap();
+    data[16..24].copy_from_slice(&u64::MAX.to_be_bytes());
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert_eq!(frame.id, 7);
+}
+
+#[test]
+fn metadata_roundtrip() {
+    use std::time::Duration;
+
+    let mut req = ReqBuf::new();
+    req.set_timeout(Some(Duration::from_secs(1)));
+    req.metadata_mut().insert("trace-id", "abc");
+    req.metadata_mut().insert("auth", vec![1, 2, 3]);
+    req.write_all(b"hello").unwrap();
+    let data = req.finish(5).unwrap();
+
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert!(frame.deadline().is_some());
+    assert_eq!(frame.metadata().len(), 2);
+    assert_eq!(frame.metadata().get("trace-id"), Some(&b"abc"[..]));
+    assert_eq!(frame.metadata().get("auth"), Some(&[1, 2, 3][..]));
+    assert_eq!(frame.decode_req(), b"hello");
+
+    let mut rsp = RspBuf::new();
+    rsp.metadata_mut().insert("content-type", "text");
+    rsp.write_all(b"world").unwrap();
+    let data = rsp.finish(5, Ok(()));
+
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert_eq!(frame.metadata().get("content-type"), Some(&b"text"[..]));
+    assert_eq!(frame.decode_rsp().unwrap(), b"world");
+}
+
+#[test]
+fn metadata_version_mismatch() {
+    let mut req = ReqBuf::new();
+    req.metadata_mut().insert("trace-id", "abc");
+    let mut data = req.finish(5).unwrap();
+    // the version byte follows the head
+    data[16] += 1;
+
+    match Frame::decode_from(&mut Cursor::new(data)) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+        ret => panic!("unexpected decode result: {ret:?}"),
+    }
+}
+
+#[test]
+fn status_roundtrip() {
+    use conetty::{StatusCode, WireError};
+
+    let rsp = RspBuf::new();
+    let ret = Err(WireError::Status {
+        code: StatusCode(1000),
+        message: "no such user".to_owned(),
+        details: vec![4, 2],
+    });
+    let data = rsp.finish(9, ret);
+
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    match frame.decode_rsp() {
+        Err(Error::Status {
+            code,
+            message,
+            details,
+        }) => {
+            assert_eq!(code, StatusCode(1000));
+            assert_eq!(message, "no such user");
+            assert_eq!(details, [4, 2]);
+        }
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    let rsp = RspBuf::new();
+    let data = rsp.finish(9, Err(WireError::status(StatusCode::UNAVAILABLE, "")));
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert!(matches!(
+        frame.decode_rsp(),
+        Err(Error::Status {
+            code: StatusCode::UNAVAILABLE,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn invalid_rsp() {
+    use conetty::WireError;
+
+    // the rsp len exceeds the frame
+    let mut rsp = RspBuf::new();
+    rsp.write_all(b"hello").unwrap();
+    let mut data = rsp.finish(1, Ok(()));
+    // the rsp len follows the 16 bytes head and the 1 byte rsp type
+    data[17..25].copy_from_slice(&u64::MAX.to_be_bytes());
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    assert!(matches!(
+        frame.decode_rsp(),
+        Err(Error::ClientDeserialize(_))
+    ));
+
+    // the error message is not utf8
+    let rsp = RspBuf::new();
+    let ret = Err(WireError::ServerDeserialize("ab".to_owned()));
+    let mut data = rsp.finish(1, ret);
+    data[25..27].copy_from_slice(&[0xff, 0xfe]);
+    let frame = Frame::decode_from(&mut Cursor::new(data)).unwrap();
+    match frame.decode_rsp() {
+        Err(Error::ServerDeserialize(s)) => assert_eq!(s, "\u{fffd}\u{fffd}"),
+        ret => panic!("unexpected decode result: {ret:?}"),
+    }
+}
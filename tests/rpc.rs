@@ -0,0 +1,120 @@
+#![cfg(feature = "bincode")]
+
+use std::time::Duration;
+
+use conetty::{Error, MultiplexClient, StatusCode, TcpServer, WireError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    x: i32,
+    y: i32,
+}
+
+conetty::service! {
+    /// a typed service for test
+    pub mod calc {
+        /// echo back the data
+        fn echo(data: String) -> String;
+        fn add(x: u32, y: u32) -> u32;
+        fn shift(p: Point, d: i32) -> Point;
+        fn ping() -> ();
+        fn div(x: u32, y: u32) -> u32;
+    }
+}
+
+struct Calc;
+
+impl calc::Service for Calc {
+    fn echo(&self, data: String) -> Result<String, WireError> {
+        Ok(data)
+    }
+
+    fn add(&self, x: u32, y: u32) -> Result<u32, WireError> {
+        Ok(x + y)
+    }
+
+    fn shift(&self, p: Point, d: i32) -> Result<Point, WireError> {
+        Ok(Point {
+            x: p.x + d,
+            y: p.y + d,
+        })
+    }
+
+    fn ping(&self) -> Result<(), WireError> {
+        Ok(())
+    }
+
+    fn div(&self, x: u32, y: u32) -> Result<u32, WireError> {
+        x.checked_div(y)
+            .ok_or_else(|| WireError::status(StatusCode::INVALID_ARGUMENT, "divide by zero"))
+    }
+}
+
+conetty::service! {
+    mod other {
+        fn echo(data: String) -> String;
+    }
+}
+
+#[test]
+fn typed_service() {
+    let addr = ("127.0.0.1", 2016);
+    let _server = calc::Server(Calc).start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let client = calc::Client::new(client);
+
+    assert_eq!(client.echo("hello".to_owned()).unwrap(), "hello");
+    assert_eq!(client.add(1, 2).unwrap(), 3);
+    let p = client.shift(Point { x: 1, y: 2 }, 3).unwrap();
+    assert_eq!(p, Point { x: 4, y: 5 });
+    client.ping().unwrap();
+    assert_eq!(client.div(6, 3).unwrap(), 2);
+    match client.div(1, 0) {
+        Err(Error::Status { code, message, .. }) => {
+            assert_eq!(code, StatusCode::INVALID_ARGUMENT);
+            assert_eq!(message, "divide by zero");
+        }
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    // the methods of another service are unknown
+    assert_ne!(other::method::echo, calc::method::echo);
+    let client = other::Client::new(client.into_inner());
+    match client.echo("hello".to_owned()) {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::UNIMPLEMENTED),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+}
+
+#[test]
+fn routed_services() {
+    use conetty::Router;
+
+    struct Other;
+
+    impl other::Service for Other {
+        fn echo(&self, data: String) -> Result<String, WireError> {
+            Ok(data.to_uppercase())
+        }
+    }
+
+    let router = Router::new();
+    router.add("calc", calc::Server(Calc)).unwrap();
+    router.add("other", other::Server(Other)).unwrap();
+    let addr = ("127.0.0.1", 2021);
+    let _server = router.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let client = calc::Client::new(client);
+    assert_eq!(client.add(1, 2).unwrap(), 3);
+    assert_eq!(client.echo("hi".to_owned()).unwrap(), "hi");
+
+    let client = other::Client::new(client.into_inner());
+    assert_eq!(client.echo("hi".to_owned()).unwrap(), "HI");
+}
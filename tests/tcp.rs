@@ -1,7 +1,7 @@
 use std::io::Write;
 use std::time::Duration;
 
-use conetty::{ReqBuf, RspBuf, Server, StreamClient, TcpServer, WireError};
+use conetty::{ReqBuf, RspBuf, Server, ServerBuilder, StreamClient, TcpServer, WireError};
 use may::{coroutine, go};
 
 struct Echo;
@@ -28,6 +28,871 @@ fn echo() {
     assert_eq!(rsp, &[5u8; 16]);
 }
 
+#[test]
+fn echo_checksum() {
+    let addr = ("127.0.0.1", 2001);
+    let _server = Echo.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    client.set_checksum(true);
+
+    let mut req = ReqBuf::new();
+    req.write_all(&[5u8; 16]).unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert!(rsp_frame.has_checksum());
+    let rsp = rsp_frame.decode_rsp().unwrap();
+    assert_eq!(rsp, &[5u8; 16]);
+}
+
+#[test]
+fn corrupted_frame() {
+    use conetty::{Error, Frame, StatusCode};
+
+    let addr = ("127.0.0.1", 2042);
+    let _server = ServerBuilder::new(Echo)
+        .require_checksum(true)
+        .start_tcp(addr)
+        .unwrap();
+
+    // the frame without a checksum is rejected
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    client.set_timeout(Duration::from_secs(2)).unwrap();
+    let rsp_frame = client.call_service(ReqBuf::new()).unwrap();
+    match rsp_frame.decode_rsp() {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::INVALID_ARGUMENT),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    // the corrupted frame is replied with the same id
+    let mut tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    tcp_stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut raw_client = StreamClient::new(tcp_stream.try_clone().unwrap());
+    raw_client.handshake().unwrap();
+    let mut req = ReqBuf::new();
+    req.set_checksum(true);
+    req.write_all(b"hello").unwrap();
+    let mut data = req.finish(7).unwrap();
+    let n = data.len();
+    data[n - 5] ^= 0xff;
+    tcp_stream.write_all(&data).unwrap();
+    let rsp_frame = Frame::decode_from(&mut tcp_stream).unwrap();
+    assert_eq!(rsp_frame.id, 7);
+    match rsp_frame.decode_rsp() {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::DATA_LOSS),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    // the checked frame is served
+    client.set_checksum(true);
+    let mut req = ReqBuf::new();
+    req.write_all(b"hello").unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert_eq!(rsp_frame.decode_rsp().unwrap(), b"hello");
+}
+
+#[test]
+fn frame_limit() {
+    let addr = ("127.0.0.1", 2002);
+    let _server = ServerBuilder::new(Echo)
+        .max_frame_len(64)
+        .max_message_len(128)
+        .start_tcp(addr)
+        .unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+
+    // the response is larger than the frame limit, split into continuation frames
+    let mut req = ReqBuf::new();
+    req.write_all(&[5u8; 40]).unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert_eq!(rsp_frame.decode_rsp().unwrap(), &[5u8; 40]);
+
+    // the response is larger than the message limit
+    let mut req = ReqBuf::new();
+    req.set_max_len(64);
+    req.write_all(&[5u8; 110]).unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert!(rsp_frame.decode_rsp().is_err());
+
+    // the server would close the connection for a too large request
+    let mut req = ReqBuf::new();
+    req.write_all(&[5u8; 64]).unwrap();
+    assert!(client.call_service(req).is_err());
+}
+
+#[test]
+fn max_connections() {
+    let addr = ("127.0.0.1", 2003);
+    let _server = ServerBuilder::new(Echo)
+        .max_connections(1)
+        .start_tcp(addr)
+        .unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    let mut req = ReqBuf::new();
+    write!(req, "aaaaaa").unwrap();
+    assert!(client.call_service(req).is_ok());
+
+    // the second connection would be rejected
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client1 = StreamClient::new(tcp_stream);
+    let mut req = ReqBuf::new();
+    write!(req, "bbbbbb").unwrap();
+    assert!(client1.call_service(req).is_err());
+}
+
+#[test]
+fn max_connections_wait() {
+    use conetty::ConnectionPolicy;
+
+    let addr = ("127.0.0.1", 2029);
+    let _server = ServerBuilder::new(Echo)
+        .max_connections(1)
+        .connection_policy(ConnectionPolicy::Wait)
+        .start_tcp(addr)
+        .unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    let mut req = ReqBuf::new();
+    write!(req, "aaaaaa").unwrap();
+    assert!(client.call_service(req).is_ok());
+
+    // the second connection waits until the first one is closed
+    let h = go!(move || {
+        let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+        let mut client = StreamClient::new(tcp_stream);
+        client.set_timeout(Duration::from_secs(2)).unwrap();
+        let mut req = ReqBuf::new();
+        write!(req, "bbbbbb").unwrap();
+        client
+            .call_service(req)
+            .map(|f| f.decode_rsp().unwrap().to_vec())
+    });
+    coroutine::sleep(Duration::from_millis(200));
+    assert!(!h.is_done());
+    drop(client);
+    assert_eq!(h.join().unwrap().unwrap(), b"bbbbbb");
+}
+
+#[test]
+fn max_connections_per_ip() {
+    let addr = ("127.0.0.1", 2030);
+    let server = ServerBuilder::new(Echo)
+        .max_connections_per_ip(1)
+        .start_tcp(addr)
+        .unwrap();
+
+    let connect = || StreamClient::new(may::net::TcpStream::connect(addr).unwrap());
+    let call = |client: &mut StreamClient<_>| {
+        let mut req = ReqBuf::new();
+        write!(req, "aaaaaa").unwrap();
+        client.call_service(req)
+    };
+
+    let mut client = connect();
+    assert!(call(&mut client).is_ok());
+
+    // the second connection from the same ip would be rejected
+    let mut client1 = connect();
+    assert!(call(&mut client1).is_err());
+    assert_eq!(server.metrics().rejected_connections, 1);
+
+    // the ip can connect again once the first connection is closed
+    drop(client);
+    coroutine::sleep(Duration::from_millis(100));
+    let mut client2 = connect();
+    assert!(call(&mut client2).is_ok());
+}
+
+#[test]
+fn idle_timeout() {
+    let addr = ("127.0.0.1", 2004);
+    let server = ServerBuilder::new(Echo)
+        .idle_timeout(Duration::from_millis(200))
+        .start_tcp(addr)
+        .unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    let mut req = ReqBuf::new();
+    write!(req, "aaaaaa").unwrap();
+    assert!(client.call_service(req).is_ok());
+
+    // the server would close the idle connection
+    coroutine::sleep(Duration::from_millis(500));
+    let mut req = ReqBuf::new();
+    write!(req, "bbbbbb").unwrap();
+    assert!(client.call_service(req).is_err());
+    assert_eq!(server.metrics().idle_timeouts, 1);
+}
+
+#[test]
+fn graceful_shutdown() {
+    use conetty::{Client, Error, MultiplexClient, StatusCode};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    struct Slow;
+
+    impl Server for Slow {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            coroutine::sleep(Duration::from_millis(300));
+            rsp.write_all(req)
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let addr = ("127.0.0.1", 2005);
+    let server = Slow.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let client = Arc::new(client);
+
+    // a client that ignores the go away
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut raw_client = StreamClient::new(tcp_stream);
+    raw_client.set_timeout(Duration::from_secs(2)).unwrap();
+    raw_client.handshake().unwrap();
+
+    let client1 = client.clone();
+    let h = go!(move || {
+        let mut req = ReqBuf::new();
+        write!(req, "aaaaaa").unwrap();
+        client1.call_service(req)
+    });
+
+    coroutine::sleep(Duration::from_millis(100));
+    let shutdown = go!(move || server.shutdown(Instant::now() + Duration::from_secs(2)));
+
+    // the connection is still served while draining
+    coroutine::sleep(Duration::from_millis(50));
+    let mut req = ReqBuf::new();
+    write!(req, "dddddd").unwrap();
+    let rsp_frame = raw_client.call_service(req).unwrap();
+    match rsp_frame.decode_rsp() {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::UNAVAILABLE),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    let report = shutdown.join().unwrap();
+    assert_eq!(report.drained, 1);
+    assert_eq!(report.aborted, 0);
+
+    // the running request is finished
+    let rsp_frame = h.join().unwrap().unwrap();
+    assert_eq!(rsp_frame.decode_rsp().unwrap(), b"aaaaaa");
+
+    // new requests would fail fast
+    let mut req = ReqBuf::new();
+    write!(req, "bbbbbb").unwrap();
+    assert!(client.call_service(req).is_err());
+}
+
+#[test]
+fn shutdown_stalled_client() {
+    use conetty::{MultiplexClient, RspSender};
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    struct Large;
+
+    impl Server for Large {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            Ok(())
+        }
+
+        fn service_stream(&self, _req: &[u8], rsp: &mut RspSender) -> Result<(), WireError> {
+            let mut buf = RspBuf::new();
+            buf.write_all(&vec![0u8; 15 * 1024 * 1024]).unwrap();
+            rsp.send(buf)
+        }
+    }
+
+    let addr = ("127.0.0.1", 2039);
+    let server = Large.start(addr).unwrap();
+
+    // a proxy that stops forwarding the rsp once stalled
+    let proxy = may::net::TcpListener::bind(("127.0.0.1", 2041)).unwrap();
+    let stalled = Arc::new(AtomicBool::new(false));
+    let stalled1 = stalled.clone();
+    go!(move || {
+        let (mut client, _) = proxy.accept().unwrap();
+        let mut server = may::net::TcpStream::connect(addr).unwrap();
+        let mut client1 = client.try_clone().unwrap();
+        let mut server1 = server.try_clone().unwrap();
+        go!(move || std::io::copy(&mut client1, &mut server1).ok());
+        let mut buf = [0u8; 1024];
+        while !stalled1.load(Ordering::Acquire) {
+            match server.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => client.write_all(&buf[..n]).unwrap(),
+            }
+        }
+    });
+
+    let tcp_stream = may::net::TcpStream::connect(("127.0.0.1", 2041)).unwrap();
+    let client = MultiplexClient::new(tcp_stream).unwrap();
+    stalled.store(true, Ordering::Release);
+    let _rx = client.call_stream(ReqBuf::new()).unwrap();
+    coroutine::sleep(Duration::from_millis(200));
+
+    // the blocked writes don't hold the shutdown past the deadline, and the
+    // request writing its item at the deadline is not cancelled
+    let now = Instant::now();
+    let report = server.shutdown(now + Duration::from_millis(300));
+    assert!(now.elapsed() < Duration::from_secs(2));
+    assert_eq!(report.aborted, 0);
+}
+
+#[test]
+fn shutdown_abort() {
+    use conetty::{Client, MultiplexClient};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    struct Sleep;
+
+    impl Server for Sleep {
+        fn service(&self, req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            coroutine::sleep(Duration::from_millis(req[0] as u64 * 100));
+            Ok(())
+        }
+    }
+
+    let addr = ("127.0.0.1", 2040);
+    let server = Sleep.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let client = Arc::new(client);
+
+    let handles: Vec<_> = [1u8, 2, 50]
+        .into_iter()
+        .map(|n| {
+            let client = client.clone();
+            go!(move || {
+                let mut req = ReqBuf::new();
+                req.write_all(&[n]).unwrap();
+                client.call_service(req)
+            })
+        })
+        .collect();
+    coroutine::sleep(Duration::from_millis(50));
+
+    // only the slowest one is cancelled at the deadline
+    let report = server.shutdown(Instant::now() + Duration::from_millis(500));
+    assert_eq!(report.drained, 2);
+    assert_eq!(report.aborted, 1);
+    for h in handles.into_iter().take(2) {
+        assert!(h.join().unwrap().is_ok());
+    }
+}
+
+#[test]
+fn stream_rsp() {
+    use conetty::{Error, MultiplexClient, RspSender, StatusCode};
+
+    struct Count;
+
+    impl Server for Count {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            Err(WireError::status(
+                StatusCode::UNIMPLEMENTED,
+                "not supported",
+            ))
+        }
+
+        fn service_stream(&self, req: &[u8], rsp: &mut RspSender) -> Result<(), WireError> {
+            for i in 0..req[0] {
+                let mut buf = RspBuf::new();
+                buf.write_all(&[i]).unwrap();
+                rsp.send(buf)?;
+            }
+            if req.len() > 1 {
+                let details = vec![req[1]];
+                return Err(WireError::Status {
+                    code: StatusCode::NOT_FOUND,
+                    message: "stream error".to_owned(),
+                    details,
+                });
+            }
+            Ok(())
+        }
+    }
+
+    let addr = ("127.0.0.1", 2006);
+    let _server = Count.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+
+    let mut req = ReqBuf::new();
+    req.write_all(&[3]).unwrap();
+    let items: Vec<u8> = client
+        .call_stream(req)
+        .unwrap()
+        .map(|frame| frame.unwrap().decode_rsp().unwrap()[0])
+        .collect();
+    assert_eq!(items, [0, 1, 2]);
+
+    // the stream is terminated by the error
+    let mut req = ReqBuf::new();
+    req.write_all(&[2, 0]).unwrap();
+    let items: Vec<_> = client.call_stream(req).unwrap().collect();
+    assert_eq!(items.len(), 3);
+    assert!(items[0].is_ok());
+    assert!(items[1].is_ok());
+    match items[2] {
+        Err(Error::Status {
+            code,
+            ref message,
+            ref details,
+        }) => {
+            assert_eq!(code, StatusCode::NOT_FOUND);
+            assert_eq!(message, "stream error");
+            assert_eq!(details, &[0]);
+        }
+        _ => panic!("unexpected stream item"),
+    }
+
+    // none streaming server would reply one item
+    let addr = ("127.0.0.1", 2007);
+    let _server = Echo.start(addr).unwrap();
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = MultiplexClient::new(tcp_stream).unwrap();
+    let mut req = ReqBuf::new();
+    req.write_all(&[5u8; 16]).unwrap();
+    let items: Vec<_> = client.call_stream(req).unwrap().collect();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].as_ref().unwrap().decode_rsp().unwrap(), &[5u8; 16]);
+}
+
+#[test]
+fn large_message() {
+    use conetty::{Client, MultiplexClient};
+
+    let addr = ("127.0.0.1", 2009);
+    let _server = Echo.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(5));
+
+    // both the req and rsp are split into continuation frames
+    let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+    let mut req = ReqBuf::new();
+    req.write_all(&data).unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert_eq!(rsp_frame.decode_rsp().unwrap(), data.as_slice());
+
+    // the connection is still usable
+    let mut req = ReqBuf::new();
+    write!(req, "aaaaaa").unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert_eq!(rsp_frame.decode_rsp().unwrap(), b"aaaaaa");
+}
+
+#[test]
+fn cancel_request() {
+    use conetty::{Client, Error, MultiplexClient, RspSender};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    static ITEMS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Slow;
+
+    impl Server for Slow {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            coroutine::sleep(Duration::from_millis(500));
+            DONE.fetch_add(1, Ordering::Relaxed);
+            rsp.write_all(req)
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+
+        fn service_stream(&self, _req: &[u8], rsp: &mut RspSender) -> Result<(), WireError> {
+            for i in 0..10u8 {
+                let mut buf = RspBuf::new();
+                buf.write_all(&[i]).unwrap();
+                rsp.send(buf)?;
+                ITEMS.fetch_add(1, Ordering::Relaxed);
+                coroutine::sleep(Duration::from_millis(100));
+            }
+            Ok(())
+        }
+    }
+
+    let addr = ("127.0.0.1", 2010);
+    let _server = Slow.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_millis(100));
+
+    // the timeout request is abandoned by the server
+    let mut req = ReqBuf::new();
+    write!(req, "aaaaaa").unwrap();
+    assert!(client.call_service(req).is_err());
+
+    // cancel the stream explicitly
+    client.set_timeout(Duration::from_secs(2));
+    let mut req = ReqBuf::new();
+    write!(req, "bbbbbb").unwrap();
+    let mut rx = client.call_stream(req).unwrap();
+    assert!(rx.next().unwrap().is_ok());
+    client.cancel(&rx);
+    assert!(matches!(rx.next(), Some(Err(Error::Cancelled))));
+    assert!(rx.next().is_none());
+
+    // cancel a call explicitly
+    let mut req = ReqBuf::new();
+    write!(req, "cccccc").unwrap();
+    let call = client.start_call(req).unwrap();
+    client.cancel_call(&call);
+    assert!(matches!(call.wait(), Err(Error::Cancelled)));
+
+    coroutine::sleep(Duration::from_millis(800));
+    assert_eq!(DONE.load(Ordering::Relaxed), 0);
+    assert!(ITEMS.load(Ordering::Relaxed) < 10);
+}
+
+#[test]
+fn drop_stream() {
+    use conetty::{MultiplexClient, RspSender};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static ITEMS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Ticker;
+
+    impl Server for Ticker {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            unreachable!()
+        }
+
+        fn service_stream(&self, _req: &[u8], rsp: &mut RspSender) -> Result<(), WireError> {
+            for i in 0..10u8 {
+                let mut buf = RspBuf::new();
+                buf.write_all(&[i]).unwrap();
+                rsp.send(buf)?;
+                ITEMS.fetch_add(1, Ordering::Relaxed);
+                coroutine::sleep(Duration::from_millis(100));
+            }
+            Ok(())
+        }
+    }
+
+    let addr = ("127.0.0.1", 2035);
+    let _server = Ticker.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = MultiplexClient::new(tcp_stream).unwrap();
+
+    // the server abandons the stream once the receiver is dropped
+    let mut rx = client.call_stream(ReqBuf::new()).unwrap();
+    assert!(rx.next().unwrap().is_ok());
+    drop(rx);
+
+    coroutine::sleep(Duration::from_millis(1200));
+    assert!(ITEMS.load(Ordering::Relaxed) < 10);
+}
+
+#[test]
+fn deadline() {
+    use conetty::{Error, RequestContext, StatusCode};
+
+    struct Remaining;
+
+    impl Server for Remaining {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            unreachable!()
+        }
+
+        fn service_with_context(
+            &self,
+            ctx: &RequestContext,
+            _req: &[u8],
+            rsp: &mut RspBuf,
+        ) -> Result<(), WireError> {
+            let remaining = ctx.remaining().map_or(0, |d| d.as_millis() as u64);
+            rsp.write_all(&remaining.to_be_bytes())
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let addr = ("127.0.0.1", 2011);
+    let _server = Remaining.start(addr).unwrap();
+
+    // the client timeout is seen by the server as the deadline
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    client.set_timeout(Duration::from_secs(2)).unwrap();
+    let rsp_frame = client.call_service(ReqBuf::new()).unwrap();
+    let rsp = rsp_frame.decode_rsp().unwrap();
+    let remaining = u64::from_be_bytes(rsp.try_into().unwrap());
+    assert!(remaining > 1000 && remaining <= 2000);
+
+    // the expired request is refused by the server
+    let mut req = ReqBuf::new();
+    req.set_timeout(Some(Duration::ZERO));
+    let rsp_frame = client.call_service(req).unwrap();
+    assert!(matches!(
+        rsp_frame.decode_rsp(),
+        Err(Error::Status {
+            code: StatusCode::DEADLINE_EXCEEDED,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn request_context() {
+    use conetty::{Peer, RequestContext};
+
+    struct Whoami;
+
+    impl Server for Whoami {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            unreachable!()
+        }
+
+        fn service_with_context(
+            &self,
+            ctx: &RequestContext,
+            _req: &[u8],
+            rsp: &mut RspBuf,
+        ) -> Result<(), WireError> {
+            assert!(ctx.conn_id().is_some());
+            assert!(!ctx.is_cancelled());
+            let peer = match ctx.peer() {
+                Peer::Inet(addr) => addr.to_string(),
+                peer => panic!("unexpected peer: {peer:?}"),
+            };
+            write!(rsp, "{} {}", ctx.id(), peer)
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let addr = ("127.0.0.1", 2012);
+    let _server = Whoami.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let local_addr = tcp_stream.local_addr().unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    client.call_service(ReqBuf::new()).unwrap();
+    let rsp_frame = client.call_service(ReqBuf::new()).unwrap();
+    let rsp = rsp_frame.decode_rsp().unwrap();
+    assert_eq!(rsp, format!("1 {local_addr}").as_bytes());
+}
+
+#[test]
+fn metadata() {
+    use conetty::RequestContext;
+
+    struct Trace;
+
+    impl Server for Trace {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            unreachable!()
+        }
+
+        fn service_with_context(
+            &self,
+            ctx: &RequestContext,
+            req: &[u8],
+            rsp: &mut RspBuf,
+        ) -> Result<(), WireError> {
+            if let Some(id) = ctx.metadata().get("trace-id") {
+                rsp.metadata_mut().insert("trace-id", id);
+            }
+            rsp.write_all(req)
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let addr = ("127.0.0.1", 2013);
+    let _server = Trace.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+
+    let mut req = ReqBuf::new();
+    req.metadata_mut().insert("trace-id", "0123456789");
+    write!(req, "aaaaaa").unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert_eq!(
+        rsp_frame.metadata().get("trace-id"),
+        Some(&b"0123456789"[..])
+    );
+    assert_eq!(rsp_frame.decode_rsp().unwrap(), b"aaaaaa");
+
+    // no metadata by default
+    let rsp_frame = client.call_service(ReqBuf::new()).unwrap();
+    assert!(rsp_frame.metadata().is_empty());
+}
+
+#[test]
+fn duplex_stream() {
+    use conetty::{MultiplexClient, ReqReceiver, RspSender, StatusCode};
+
+    struct Sum;
+
+    impl Server for Sum {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            Err(WireError::status(
+                StatusCode::UNIMPLEMENTED,
+                "not supported",
+            ))
+        }
+
+        // reply the running sum for each item and the total at the end
+        fn service_duplex(
+            &self,
+            reqs: &mut ReqReceiver,
+            rsp: &mut RspSender,
+        ) -> Result<(), WireError> {
+            let mut sum = 0u8;
+            for req in reqs {
+                sum += req.decode_req()[0];
+                let mut buf = RspBuf::new();
+                buf.write_all(&[sum]).unwrap();
+                rsp.send(buf)?;
+            }
+            let mut buf = RspBuf::new();
+            buf.write_all(&[sum, 0xff]).unwrap();
+            rsp.send(buf)
+        }
+    }
+
+    let addr = ("127.0.0.1", 2008);
+    let _server = Sum.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+
+    // exchange the items one by one
+    let (mut tx, mut rx) = client.open_stream().unwrap();
+    for i in 1..4u8 {
+        let mut req = ReqBuf::new();
+        req.write_all(&[i]).unwrap();
+        tx.send(req).unwrap();
+        let frame = rx.next().unwrap().unwrap();
+        assert_eq!(frame.decode_rsp().unwrap(), &[i * (i + 1) / 2]);
+    }
+    // half-close the req stream, the rsp stream is still alive
+    tx.finish();
+    let frame = rx.next().unwrap().unwrap();
+    assert_eq!(frame.decode_rsp().unwrap(), &[6, 0xff]);
+    assert!(rx.next().is_none());
+
+    // upload the items and then get the result
+    let (mut tx, rx) = client.open_stream().unwrap();
+    for i in 0..10u8 {
+        let mut req = ReqBuf::new();
+        req.write_all(&[i]).unwrap();
+        tx.send(req).unwrap();
+    }
+    drop(tx);
+    let last = rx.last().unwrap().unwrap();
+    assert_eq!(last.decode_rsp().unwrap(), &[45, 0xff]);
+
+    // an empty stream
+    let (tx, rx) = client.open_stream().unwrap();
+    tx.finish();
+    let items: Vec<_> = rx.collect();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].as_ref().unwrap().decode_rsp().unwrap(), &[0, 0xff]);
+}
+
+#[test]
+fn stream_context() {
+    use conetty::{Error, MultiplexClient, ReqReceiver, RequestContext, RspSender, StatusCode};
+
+    struct Ticker;
+
+    impl Ticker {
+        // send the conn id and then an item every 100ms
+        fn tick(&self, ctx: &RequestContext, rsp: &mut RspSender) -> Result<(), WireError> {
+            let mut buf = RspBuf::new();
+            buf.write_all(&[ctx.conn_id().is_some() as u8]).unwrap();
+            rsp.send(buf)?;
+            for i in 0..10u8 {
+                coroutine::sleep(Duration::from_millis(100));
+                let mut buf = RspBuf::new();
+                buf.write_all(&[i]).unwrap();
+                rsp.send(buf)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl Server for Ticker {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            unreachable!()
+        }
+
+        fn service_stream_with_context(
+            &self,
+            ctx: &RequestContext,
+            _req: &[u8],
+            rsp: &mut RspSender,
+        ) -> Result<(), WireError> {
+            self.tick(ctx, rsp)
+        }
+
+        fn service_duplex_with_context(
+            &self,
+            ctx: &RequestContext,
+            _reqs: &mut ReqReceiver,
+            rsp: &mut RspSender,
+        ) -> Result<(), WireError> {
+            self.tick(ctx, rsp)
+        }
+    }
+
+    // the stream is ended once the deadline is expired
+    fn check_deadline(items: Vec<Result<conetty::Frame, Error>>) {
+        assert_eq!(items[0].as_ref().unwrap().decode_rsp().unwrap(), &[1]);
+        assert!(items.len() < 11);
+        match items.last().unwrap() {
+            Err(Error::Status { code, .. }) => assert_eq!(*code, StatusCode::DEADLINE_EXCEEDED),
+            r => panic!("unexpected rsp: {r:?}"),
+        }
+    }
+
+    let addr = ("127.0.0.1", 2036);
+    let _server = Ticker.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = MultiplexClient::new(tcp_stream).unwrap();
+
+    let mut req = ReqBuf::new();
+    req.set_timeout(Some(Duration::from_millis(250)));
+    let rx = client.call_stream(req).unwrap();
+    check_deadline(rx.collect());
+
+    // the deadline of a duplex stream is set by its first item
+    let (mut tx, rx) = client.open_stream().unwrap();
+    let mut req = ReqBuf::new();
+    req.set_timeout(Some(Duration::from_millis(250)));
+    tx.send(req).unwrap();
+    check_deadline(rx.collect());
+}
+
 #[test]
 fn tcp_timeout() {
     struct Echo;
@@ -92,3 +957,845 @@ fn multi_client() {
 
     assert_eq!(count.load(Ordering::Relaxed), 80);
 }
+
+#[test]
+fn handshake() {
+    use conetty::{
+        Client, Error, Features, MultiplexClient, StatusCode, PREFACE_MAGIC, PROTOCOL_VERSION,
+    };
+    use std::io::Read;
+
+    let addr = ("127.0.0.1", 2014);
+    let _server = Echo.start(addr).unwrap();
+
+    // the negotiated features
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = MultiplexClient::new(tcp_stream).unwrap();
+    assert!(client.features().contains(Features::all()));
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = StreamClient::new(tcp_stream);
+    let features = Features::all().difference(Features::HEARTBEAT);
+    assert_eq!(client.handshake().unwrap(), features);
+
+    // the client offers only some features
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let offer = Features::all().difference(Features::STREAMING);
+    let client = MultiplexClient::with_features(tcp_stream, offer).unwrap();
+    assert_eq!(client.features(), offer);
+    assert!(matches!(client.open_stream(), Err(Error::Handshake(_))));
+
+    // the server offers only some features
+    let addr2 = ("127.0.0.1", 2044);
+    let _server2 = ServerBuilder::new(Echo)
+        .features(Features::all().difference(Features::CHECKSUM))
+        .start_tcp(addr2)
+        .unwrap();
+    let tcp_stream = may::net::TcpStream::connect(addr2).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    assert!(!client.features().contains(Features::CHECKSUM));
+    // the checksum not negotiated is left out
+    client.set_checksum(true);
+    let mut req = ReqBuf::new();
+    req.write_all(b"hello").unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    assert!(!rsp_frame.has_checksum());
+    assert_eq!(rsp_frame.decode_rsp().unwrap(), b"hello");
+
+    // and rejected by the server
+    let mut tcp_stream = may::net::TcpStream::connect(addr2).unwrap();
+    tcp_stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut raw_client = StreamClient::new(tcp_stream.try_clone().unwrap());
+    raw_client.handshake().unwrap();
+    let mut req = ReqBuf::new();
+    req.set_checksum(true);
+    tcp_stream.write_all(&req.finish(3).unwrap()).unwrap();
+    let rsp_frame = conetty::Frame::decode_from(&mut tcp_stream).unwrap();
+    assert_eq!(rsp_frame.id, 3);
+    match rsp_frame.decode_rsp() {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::INVALID_ARGUMENT),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    // a peer without the preface is closed
+    let mut tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    tcp_stream.write_all(&[0u8; 32]).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(tcp_stream.read_to_end(&mut buf).unwrap_or(0), 0);
+
+    // the server replies its preface before closing a newer client
+    let mut tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut preface = PREFACE_MAGIC.to_vec();
+    preface.push(PROTOCOL_VERSION + 1);
+    preface.extend_from_slice(&Features::all().bits().to_be_bytes());
+    tcp_stream.write_all(&preface).unwrap();
+    let mut buf = Vec::new();
+    tcp_stream.read_to_end(&mut buf).unwrap();
+    assert_eq!(&buf[..4], &PREFACE_MAGIC);
+    assert_eq!(buf[4], PROTOCOL_VERSION);
+    assert_eq!(buf.len(), 9);
+
+    // the client reports the version mismatch of the server
+    let listener = may::net::TcpListener::bind(("127.0.0.1", 2015)).unwrap();
+    let fake = go!(move || {
+        let (mut s, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 9];
+        s.read_exact(&mut buf).unwrap();
+        buf[4] = PROTOCOL_VERSION + 1;
+        s.write_all(&buf).unwrap();
+    });
+    let tcp_stream = may::net::TcpStream::connect(("127.0.0.1", 2015)).unwrap();
+    match MultiplexClient::new(tcp_stream) {
+        Err(Error::VersionMismatch { local, remote }) => {
+            assert_eq!(local, PROTOCOL_VERSION);
+            assert_eq!(remote, PROTOCOL_VERSION + 1);
+        }
+        r => panic!("unexpected handshake result: {:?}", r.err()),
+    }
+    fake.join().unwrap();
+}
+
+#[test]
+fn router() {
+    use conetty::{method_id, Client, Error, MultiplexClient, Router, StatusCode};
+
+    struct Upper;
+
+    impl Server for Upper {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            rsp.write_all(&req.to_ascii_uppercase())
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let router = Router::new();
+    router.add("echo", Echo).unwrap();
+    router.add("upper", Upper).unwrap();
+    router
+        .add_fn("text.len", |_ctx, req, rsp| {
+            rsp.write_all(&[req.len() as u8])
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        })
+        .unwrap();
+    let addr = ("127.0.0.1", 2020);
+    let _server = router.clone().start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let call = |method: &str| {
+        let mut req = ReqBuf::new();
+        req.set_method(method);
+        req.write_all(b"abc").unwrap();
+        let frame = client.call_service(req)?;
+        frame.decode_rsp().map(|rsp| rsp.to_vec())
+    };
+
+    assert_eq!(call("echo").unwrap(), b"abc");
+    assert_eq!(call("upper").unwrap(), b"ABC");
+    assert_eq!(call("text.len").unwrap(), [3]);
+    // the method falls back to the route of its service
+    assert_eq!(call("upper.get").unwrap(), b"ABC");
+
+    // route by the method id
+    let mut req = ReqBuf::new();
+    req.set_method_id(method_id("upper"));
+    req.write_all(b"xy").unwrap();
+    let frame = client.call_service(req).unwrap();
+    assert_eq!(frame.decode_rsp().unwrap(), b"XY");
+
+    let unimplemented = |r: Result<Vec<u8>, Error>| {
+        matches!(
+            r,
+            Err(Error::Status {
+                code: StatusCode::UNIMPLEMENTED,
+                ..
+            })
+        )
+    };
+    assert!(unimplemented(call("lower")));
+    assert!(unimplemented(
+        client
+            .call_service(ReqBuf::new())
+            .and_then(|f| f.decode_rsp().map(|r| r.to_vec()))
+    ));
+
+    // the routes are changed at runtime
+    assert!(router.remove("upper"));
+    assert!(!router.contains("upper"));
+    assert!(unimplemented(call("upper")));
+    assert!(!router.add("upper", Echo).unwrap());
+    assert!(router.add("upper", Echo).unwrap());
+    assert_eq!(call("upper").unwrap(), b"abc");
+}
+
+#[test]
+fn layers() {
+    use conetty::{Client, Error, Layer, MultiplexClient, Next, RequestContext, StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    // record the order of the layers
+    struct Log(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Layer for Log {
+        fn call(
+            &self,
+            ctx: &RequestContext,
+            req: &[u8],
+            rsp: &mut RspBuf,
+            next: Next<'_>,
+        ) -> Result<(), WireError> {
+            self.1.lock().unwrap().push(format!("{} before", self.0));
+            let ret = next.run(ctx, req, rsp);
+            let ok = if ret.is_ok() { "ok" } else { "err" };
+            self.1
+                .lock()
+                .unwrap()
+                .push(format!("{} after {ok}", self.0));
+            ret
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let addr = ("127.0.0.1", 2022);
+    let _server = ServerBuilder::new(Echo)
+        .layer(Log("outer", log.clone()))
+        .layer(
+            |ctx: &RequestContext, req: &[u8], rsp: &mut RspBuf, next: Next<'_>| {
+                if ctx.metadata().get("token") != Some(&b"secret"[..]) {
+                    return Err(WireError::status(StatusCode::UNKNOWN, "unauthorized"));
+                }
+                next.run(ctx, req, rsp)
+            },
+        )
+        .layer(Log("inner", log.clone()))
+        .layer(
+            |ctx: &RequestContext, req: &[u8], rsp: &mut RspBuf, next: Next<'_>| {
+                // replace the req and tag the rsp
+                let req = req.to_ascii_uppercase();
+                let ret = next.run(ctx, &req, rsp);
+                rsp.metadata_mut().insert("served-by", "layer");
+                ret
+            },
+        )
+        .start_tcp(addr)
+        .unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+
+    let mut req = ReqBuf::new();
+    req.metadata_mut().insert("token", "secret");
+    req.write_all(b"abc").unwrap();
+    let frame = client.call_service(req).unwrap();
+    assert_eq!(frame.decode_rsp().unwrap(), b"ABC");
+    assert_eq!(frame.metadata().get("served-by"), Some(&b"layer"[..]));
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "outer before",
+            "inner before",
+            "inner after ok",
+            "outer after ok"
+        ]
+    );
+
+    // rejected by the auth layer before reaching the inner layers
+    log.lock().unwrap().clear();
+    let mut req = ReqBuf::new();
+    req.write_all(b"abc").unwrap();
+    let frame = client.call_service(req).unwrap();
+    assert!(matches!(frame.decode_rsp(), Err(Error::Status { .. })));
+    assert_eq!(*log.lock().unwrap(), ["outer before", "outer after err"]);
+
+    // the streams pass the layers too
+    let mut req = ReqBuf::new();
+    req.metadata_mut().insert("token", "secret");
+    req.write_all(b"abc").unwrap();
+    let items: Vec<_> = client.call_stream(req).unwrap().collect();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].as_ref().unwrap().decode_rsp().unwrap(), b"ABC");
+
+    let mut req = ReqBuf::new();
+    req.write_all(b"abc").unwrap();
+    let items: Vec<_> = client.call_stream(req).unwrap().collect();
+    assert_eq!(items.len(), 1);
+    assert!(matches!(items[0], Err(Error::Status { .. })));
+
+    let (mut tx, rx) = client.open_stream().unwrap();
+    let mut req = ReqBuf::new();
+    req.write_all(b"abc").unwrap();
+    tx.send(req).unwrap();
+    let items: Vec<_> = rx.collect();
+    assert_eq!(items.len(), 1);
+    assert!(matches!(items[0], Err(Error::Status { .. })));
+}
+
+#[test]
+fn client_layers() {
+    use conetty::{
+        Client, ClientExt, DefaultHeaders, DefaultTimeout, Error, Logging, Metadata, Metrics,
+        MultiplexClient, RequestContext, Retry, StatusCode,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // fail the first requests, and reply the token and the deadline of each request
+    struct Flaky(AtomicUsize);
+
+    impl Server for Flaky {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            unreachable!()
+        }
+
+        fn service_with_context(
+            &self,
+            ctx: &RequestContext,
+            req: &[u8],
+            rsp: &mut RspBuf,
+        ) -> Result<(), WireError> {
+            if req == b"sleep" {
+                coroutine::sleep(Duration::from_millis(500));
+            }
+            if self.0.fetch_sub(1, Ordering::Relaxed) > 0 {
+                return Err(WireError::status(StatusCode::UNAVAILABLE, "try later"));
+            }
+            self.0.store(0, Ordering::Relaxed);
+            let remaining = ctx.remaining().map_or(0, |d| d.as_millis() as u64);
+            rsp.write_all(ctx.metadata().get("token").unwrap_or_default())
+                .and_then(|_| rsp.write_all(&remaining.to_be_bytes()))
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let addr = ("127.0.0.1", 2023);
+    let _server = Flaky(AtomicUsize::new(2)).start(addr).unwrap();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let mut headers = Metadata::new();
+    headers.insert("token", "abc");
+    let metrics = Metrics::new();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let client = client
+        .with_layer(move |req: ReqBuf, next: &dyn Client| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            next.call_service(req)
+        })
+        .with_layer(Retry::new(3).backoff(Duration::from_millis(10)))
+        .with_layer(DefaultHeaders(headers))
+        .with_layer(DefaultTimeout(Duration::from_millis(300)))
+        .with_layer(metrics.clone())
+        .with_layer(Logging);
+
+    // the layered client is still a client
+    fn call<C: Client>(client: &C, req: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut buf = ReqBuf::new();
+        buf.write_all(req).unwrap();
+        let frame = client.call_service(buf)?;
+        frame.decode_rsp().map(|rsp| rsp.to_vec())
+    }
+
+    // retried twice before success
+    let rsp = call(&client, b"hello").unwrap();
+    assert_eq!(calls.load(Ordering::Relaxed), 3);
+    assert_eq!(&rsp[..3], b"abc");
+    let remaining = u64::from_be_bytes(rsp[3..].try_into().unwrap());
+    assert!(remaining > 0 && remaining <= 300);
+
+    // the req timeout is used to wait the rsp, the timeout is not retried
+    calls.store(0, Ordering::Relaxed);
+    let now = std::time::Instant::now();
+    assert!(call(&client, b"sleep").is_err());
+    assert!(now.elapsed() < Duration::from_millis(450));
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+    // the retries are counted as one call
+    let m = metrics.get();
+    assert_eq!((m.calls, m.errors), (2, 1));
+    assert!(m.latency >= Duration::from_millis(300));
+}
+
+#[test]
+fn panic_isolation() {
+    use conetty::{Client, Error, MultiplexClient, RspSender, StatusCode};
+
+    struct Buggy;
+
+    impl Server for Buggy {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            if req == b"panic" {
+                panic!("buggy handler");
+            }
+            if req == b"panic_any" {
+                std::panic::panic_any(42u32);
+            }
+            rsp.write_all(req)
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+
+        fn service_stream(&self, _req: &[u8], rsp: &mut RspSender) -> Result<(), WireError> {
+            rsp.send(RspBuf::new())?;
+            panic!("buggy stream {}", 1);
+        }
+    }
+
+    let addr = ("127.0.0.1", 2025);
+    let server = Buggy.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+
+    let mut req = ReqBuf::new();
+    req.write_all(b"panic").unwrap();
+    let frame = client.call_service(req).unwrap();
+    match frame.decode_rsp() {
+        Err(Error::Status { code, message, .. }) => {
+            assert_eq!(code, StatusCode::INTERNAL);
+            assert!(message.contains("buggy handler"));
+        }
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    // a panic without a message
+    let mut req = ReqBuf::new();
+    req.write_all(b"panic_any").unwrap();
+    let frame = client.call_service(req).unwrap();
+    match frame.decode_rsp() {
+        Err(Error::Status { code, message, .. }) => {
+            assert_eq!(code, StatusCode::INTERNAL);
+            assert!(message.contains("unknown panic payload"));
+        }
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+
+    // the stream is ended by the panic
+    let items: Vec<_> = client.call_stream(ReqBuf::new()).unwrap().collect();
+    assert_eq!(items.len(), 2);
+    assert!(items[0].is_ok());
+    assert!(matches!(items[1], Err(Error::Status { code, .. }) if code == StatusCode::INTERNAL));
+    assert_eq!(server.panics(), 3);
+
+    // the connection is still serving
+    let mut req = ReqBuf::new();
+    req.write_all(b"hello").unwrap();
+    let frame = client.call_service(req).unwrap();
+    assert_eq!(frame.decode_rsp().unwrap(), b"hello");
+}
+
+#[test]
+fn inflight_limits() {
+    use conetty::{Client, Error, InflightPolicy, MultiplexClient, StatusCode};
+
+    struct Slow;
+
+    impl Server for Slow {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            coroutine::sleep(Duration::from_millis(200));
+            rsp.write_all(req)
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    fn call(client: &MultiplexClient<may::net::TcpStream>) -> Result<Vec<u8>, Error> {
+        let mut req = ReqBuf::new();
+        req.write_all(b"hello").unwrap();
+        let frame = client.call_service(req)?;
+        frame.decode_rsp().map(|r| r.to_vec())
+    }
+
+    let addr = ("127.0.0.1", 2026);
+    let _server = ServerBuilder::new(Slow)
+        .max_inflight_total(1)
+        .inflight_policy(InflightPolicy::Reject)
+        .start_tcp(addr)
+        .unwrap();
+
+    let connect = || {
+        let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+        let mut client = MultiplexClient::new(tcp_stream).unwrap();
+        client.set_timeout(Duration::from_secs(2));
+        client
+    };
+    let client1 = connect();
+    let client2 = connect();
+
+    // the limit is shared by all the connections
+    let h = go!(move || call(&client1));
+    coroutine::sleep(Duration::from_millis(50));
+    match call(&client2) {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::RESOURCE_EXHAUSTED),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+    assert_eq!(h.join().unwrap().unwrap(), b"hello");
+    // the permit is released once the request is done
+    assert_eq!(call(&client2).unwrap(), b"hello");
+}
+
+#[test]
+fn inflight_backpressure() {
+    use conetty::{Client, MultiplexClient};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    struct Slow;
+
+    impl Server for Slow {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            coroutine::sleep(Duration::from_millis(100));
+            Ok(())
+        }
+    }
+
+    let addr = ("127.0.0.1", 2027);
+    let _server = ServerBuilder::new(Slow)
+        .max_inflight(1)
+        .start_tcp(addr)
+        .unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let client = Arc::new(client);
+
+    // the requests are queued instead of rejected
+    let now = Instant::now();
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let client = client.clone();
+            go!(move || client.call_service(ReqBuf::new()).unwrap())
+        })
+        .collect();
+    for h in handles {
+        assert!(h.join().unwrap().decode_rsp().is_ok());
+    }
+    assert!(now.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn inflight_duplex() {
+    use conetty::{Client, MultiplexClient, ReqReceiver, RspSender};
+    use std::sync::Arc;
+
+    struct Echo;
+
+    impl Server for Echo {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            rsp.write_all(req).unwrap();
+            Ok(())
+        }
+
+        fn service_duplex(
+            &self,
+            reqs: &mut ReqReceiver,
+            rsp: &mut RspSender,
+        ) -> Result<(), WireError> {
+            for req in reqs {
+                let mut buf = RspBuf::new();
+                buf.write_all(req.decode_req()).unwrap();
+                rsp.send(buf)?;
+            }
+            Ok(())
+        }
+    }
+
+    let addr = ("127.0.0.1", 2038);
+    let _server = ServerBuilder::new(Echo)
+        .max_inflight(1)
+        .start_tcp(addr)
+        .unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client.set_timeout(Duration::from_secs(2));
+    let client = Arc::new(client);
+
+    // the open duplex stream holds the only permit
+    let (mut tx, mut rx) = client.open_stream().unwrap();
+    let mut req = ReqBuf::new();
+    req.write_all(b"a").unwrap();
+    tx.send(req).unwrap();
+    assert_eq!(rx.next().unwrap().unwrap().decode_rsp().unwrap(), b"a");
+
+    // the unary request waits for the permit
+    let unary = {
+        let client = client.clone();
+        go!(move || {
+            let mut req = ReqBuf::new();
+            req.write_all(b"hello").unwrap();
+            client.call_service(req)
+        })
+    };
+    coroutine::sleep(Duration::from_millis(100));
+
+    // the duplex stream is still served
+    let mut req = ReqBuf::new();
+    req.write_all(b"b").unwrap();
+    tx.send(req).unwrap();
+    assert_eq!(rx.next().unwrap().unwrap().decode_rsp().unwrap(), b"b");
+    tx.finish();
+    assert!(rx.next().is_none());
+
+    // and the unary request runs once the stream is done
+    let rsp = unary.join().unwrap().unwrap();
+    assert_eq!(rsp.decode_rsp().unwrap(), b"hello");
+}
+
+#[test]
+fn read_write_timeout() {
+    use conetty::{Features, PREFACE_MAGIC, PROTOCOL_VERSION};
+    use std::io::Read;
+
+    struct Large;
+
+    impl Server for Large {
+        fn service(&self, _req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            rsp.write_all(&vec![0u8; 8 * 1024 * 1024])
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let addr = ("127.0.0.1", 2028);
+    let server = ServerBuilder::new(Large)
+        .read_timeout(Duration::from_millis(100))
+        .write_timeout(Duration::from_millis(100))
+        .start_tcp(addr)
+        .unwrap();
+
+    let connect = || {
+        let mut s = std::net::TcpStream::connect(addr).unwrap();
+        s.write_all(&PREFACE_MAGIC).unwrap();
+        s.write_all(&[PROTOCOL_VERSION]).unwrap();
+        s.write_all(&Features::all().bits().to_be_bytes()).unwrap();
+        let mut preface = [0u8; 9];
+        s.read_exact(&mut preface).unwrap();
+        s
+    };
+
+    // the frame stalls after the first bytes
+    let mut s = connect();
+    s.write_all(&[0u8; 2]).unwrap();
+    coroutine::sleep(Duration::from_millis(300));
+    assert_eq!(s.read(&mut [0u8; 16]).unwrap(), 0);
+    assert_eq!(server.metrics().read_timeouts, 1);
+
+    // the frame trickles in, each read is within the timeout but the whole frame is not
+    let mut s = connect();
+    let frame = ReqBuf::new().finish(0).unwrap();
+    for b in &frame[..10] {
+        if s.write_all(&[*b]).is_err() {
+            break;
+        }
+        coroutine::sleep(Duration::from_millis(40));
+    }
+    coroutine::sleep(Duration::from_millis(20));
+    assert!(!matches!(s.read(&mut [0u8; 16]), Ok(n) if n > 0));
+    assert_eq!(server.metrics().read_timeouts, 2);
+
+    // the client doesn't read the responses
+    let mut s = connect();
+    for id in 0..4 {
+        s.write_all(&ReqBuf::new().finish(id).unwrap()).unwrap();
+    }
+    coroutine::sleep(Duration::from_millis(1000));
+    let metrics = server.metrics();
+    assert_eq!(metrics.write_timeouts, 1);
+    assert_eq!(metrics.read_timeouts, 2);
+    assert_eq!(metrics.idle_timeouts, 0);
+}
+
+#[test]
+fn heartbeat() {
+    use conetty::{Client, Error, Features, MultiplexClient, StatusCode};
+    use conetty::{PREFACE_MAGIC, PROTOCOL_VERSION};
+    use std::io::Read;
+
+    let addr = ("127.0.0.1", 2031);
+    let server = ServerBuilder::new(Echo)
+        .heartbeat(Duration::from_millis(50), Duration::from_millis(200))
+        .start_tcp(addr)
+        .unwrap();
+
+    // the pings keep both sides alive
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client
+        .set_heartbeat(Duration::from_millis(50), Duration::from_millis(200))
+        .unwrap();
+    coroutine::sleep(Duration::from_millis(500));
+    let mut req = ReqBuf::new();
+    write!(req, "aaaaaa").unwrap();
+    assert_eq!(
+        client.call_service(req).unwrap().decode_rsp().unwrap(),
+        b"aaaaaa"
+    );
+    assert!(!client.is_closed());
+
+    // the server closes the client that doesn't reply the pings
+    let mut s = std::net::TcpStream::connect(addr).unwrap();
+    s.write_all(&PREFACE_MAGIC).unwrap();
+    s.write_all(&[PROTOCOL_VERSION]).unwrap();
+    s.write_all(&Features::all().bits().to_be_bytes()).unwrap();
+    let mut buf = Vec::new();
+    s.read_to_end(&mut buf).unwrap();
+    assert!(buf.len() > 9);
+    assert_eq!(server.metrics().heartbeat_timeouts, 1);
+
+    // the client fails the waiting calls once the server is silent
+    let listener = may::net::TcpListener::bind(("127.0.0.1", 2032)).unwrap();
+    let fake = go!(move || {
+        let (mut s, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 9];
+        s.read_exact(&mut buf).unwrap();
+        s.write_all(&buf).unwrap();
+        coroutine::sleep(Duration::from_millis(1000));
+    });
+    let tcp_stream = may::net::TcpStream::connect(("127.0.0.1", 2032)).unwrap();
+    let mut client = MultiplexClient::new(tcp_stream).unwrap();
+    client
+        .set_heartbeat(Duration::from_millis(50), Duration::from_millis(200))
+        .unwrap();
+    match client.call_service(ReqBuf::new()) {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::UNAVAILABLE),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+    assert!(client.is_closed());
+    assert!(client.call_service(ReqBuf::new()).is_err());
+    fake.join().unwrap();
+}
+
+#[test]
+fn connection_closed() {
+    use conetty::{Client, Error, MultiplexClient};
+    use std::io::{ErrorKind, Read};
+    use std::time::Instant;
+
+    // the fake server closes the connection after getting the requests
+    let listener = may::net::TcpListener::bind(("127.0.0.1", 2033)).unwrap();
+    let fake = go!(move || {
+        let (mut s, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 9];
+        s.read_exact(&mut buf).unwrap();
+        s.write_all(&buf).unwrap();
+        assert!(s.read(&mut [0u8; 1024]).unwrap() > 0);
+        coroutine::sleep(Duration::from_millis(100));
+    });
+
+    // no timeout is set, the waiting calls are failed by the closed connection
+    let tcp_stream = may::net::TcpStream::connect(("127.0.0.1", 2033)).unwrap();
+    let client = MultiplexClient::new(tcp_stream).unwrap();
+    let mut items = client.call_stream(ReqBuf::new()).unwrap();
+    let now = Instant::now();
+    match client.call_service(ReqBuf::new()) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::ConnectionAborted),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+    assert!(now.elapsed() < Duration::from_secs(1));
+    assert!(matches!(items.next(), Some(Err(Error::Io(_)))));
+    fake.join().unwrap();
+
+    // the new calls fail fast
+    assert!(client.is_closed());
+    assert!(client.call_service(ReqBuf::new()).is_err());
+    assert!(client.call_stream(ReqBuf::new()).is_err());
+}
+
+#[test]
+fn reconnecting_client() {
+    use conetty::{Client, ConnState, Error, ReconnectingClient, StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    let addr = ("127.0.0.1", 2034);
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let on_state = states.clone();
+    let client = ReconnectingClient::tcp(addr)
+        .unwrap()
+        .backoff(Duration::from_millis(50), Duration::from_millis(100))
+        .timeout(Duration::from_secs(2))
+        .on_state(move |s| on_state.lock().unwrap().push(s));
+    let call = || {
+        let mut req = ReqBuf::new();
+        write!(req, "aaaaaa").unwrap();
+        client.call_service(req)
+    };
+
+    // no server yet, the next connect waits for the backoff
+    assert!(matches!(call(), Err(Error::Io(_))));
+    match call() {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::UNAVAILABLE),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+    assert_eq!(client.state(), ConnState::Disconnected);
+
+    // connected after the backoff
+    let _server = ServerBuilder::new(Echo)
+        .idle_timeout(Duration::from_millis(100))
+        .start_tcp(addr)
+        .unwrap();
+    coroutine::sleep(Duration::from_millis(100));
+    assert_eq!(call().unwrap().decode_rsp().unwrap(), b"aaaaaa");
+    assert_eq!(client.state(), ConnState::Connected);
+
+    // reconnected after the server closes the idle connection
+    coroutine::sleep(Duration::from_millis(300));
+    assert_eq!(call().unwrap().decode_rsp().unwrap(), b"aaaaaa");
+
+    use ConnState::*;
+    let states = states.lock().unwrap();
+    assert_eq!(
+        *states,
+        [
+            Connecting,
+            Disconnected,
+            Connecting,
+            Connected,
+            Disconnected,
+            Connecting,
+            Connected
+        ]
+    );
+}
+
+#[test]
+fn reconnecting_client_connect_timeout() {
+    use conetty::{Client, ConnState, ReconnectingClient};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    // a server that accepts but never replies the preface
+    let addr = ("127.0.0.1", 2037);
+    let listener = std::net::TcpListener::bind(addr).unwrap();
+    let _silent = std::thread::spawn(move || {
+        let conns: Vec<_> = listener.incoming().take(1).collect();
+        std::thread::sleep(Duration::from_secs(2));
+        drop(conns);
+    });
+
+    let client = ReconnectingClient::tcp(addr)
+        .unwrap()
+        .connect_timeout(Duration::from_millis(300));
+    let client = Arc::new(client);
+
+    let client1 = client.clone();
+    let h = go!(move || {
+        let start = Instant::now();
+        let ret = client1.call_service(ReqBuf::new());
+        (ret, start.elapsed())
+    });
+
+    // the state is not blocked by the connect
+    coroutine::sleep(Duration::from_millis(100));
+    assert_eq!(client.state(), ConnState::Connecting);
+
+    let (ret, elapsed) = h.join().unwrap();
+    assert!(ret.is_err());
+    assert!(elapsed < Duration::from_secs(1));
+    assert_eq!(client.state(), ConnState::Disconnected);
+}
@@ -26,6 +26,50 @@ fn echo() {
     assert_eq!(rsp, &[5u8; 16]);
 }
 
+#[test]
+fn large_rsp() {
+    let addr = ("127.0.0.1", 2045);
+    let _server = Echo.start(addr).unwrap();
+    let mut client = UdpClient::connect(addr).unwrap();
+
+    // the rsp is larger than 1 KiB, but still fits in one datagram
+    let mut req = ReqBuf::new();
+    req.write_all(&[7u8; 32 * 1024]).unwrap();
+    let rsp_frame = client.call_service(req).unwrap();
+    let rsp = rsp_frame.decode_rsp().unwrap();
+    assert_eq!(rsp, &[7u8; 32 * 1024][..]);
+}
+
+#[test]
+fn corrupted_frame() {
+    use conetty::{Error, Frame, StatusCode};
+    use std::io::Cursor;
+
+    let addr = ("127.0.0.1", 2043);
+    let _server = Echo.start(addr).unwrap();
+    let sock = may::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.connect(addr).unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+    // the corrupted frame is replied with the same id
+    let mut req = ReqBuf::new();
+    req.set_checksum(true);
+    req.write_all(b"hello").unwrap();
+    let mut data = req.finish(7).unwrap();
+    let n = data.len();
+    data[n - 5] ^= 0xff;
+    sock.send(&data).unwrap();
+
+    let mut buf = vec![0u8; 1024];
+    let len = sock.recv(&mut buf).unwrap();
+    let rsp_frame = Frame::decode_from(&mut Cursor::new(&buf[..len])).unwrap();
+    assert_eq!(rsp_frame.id, 7);
+    match rsp_frame.decode_rsp() {
+        Err(Error::Status { code, .. }) => assert_eq!(code, StatusCode::DATA_LOSS),
+        r => panic!("unexpected rsp: {r:?}"),
+    }
+}
+
 #[test]
 fn tcp_timeout() {
     struct Echo;
@@ -82,7 +126,7 @@ fn multi_client() {
         vec.push(h);
     }
 
-    for (_i, j) in vec.into_iter().enumerate() {
+    for j in vec {
         j.join().unwrap();
     }
 
@@ -29,6 +29,44 @@ fn echo() {
     assert_eq!(rsp, &[5u8; 16]);
 }
 
+#[test]
+fn peer_cred() {
+    use conetty::{Peer, RequestContext};
+
+    struct Whoami;
+
+    impl Server for Whoami {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            unreachable!()
+        }
+
+        fn service_with_context(
+            &self,
+            ctx: &RequestContext,
+            _req: &[u8],
+            rsp: &mut RspBuf,
+        ) -> Result<(), WireError> {
+            let pid = match ctx.peer() {
+                Peer::Unix(cred) => cred.pid.unwrap_or(0),
+                peer => panic!("unexpected peer: {peer:?}"),
+            };
+            rsp.write_all(&pid.to_be_bytes())
+                .map_err(|e| WireError::ServerSerialize(e.to_string()))
+        }
+    }
+
+    let path = "/tmp/test_uds_cred";
+    let _server = Whoami.start(path).unwrap();
+    let unix_stream = may::os::unix::net::UnixStream::connect(path).unwrap();
+    let mut client = StreamClient::new(unix_stream);
+
+    let rsp_frame = client.call_service(ReqBuf::new()).unwrap();
+    let pid = i32::from_be_bytes(rsp_frame.decode_rsp().unwrap().try_into().unwrap());
+    if cfg!(target_os = "linux") {
+        assert_eq!(pid, std::process::id() as i32);
+    }
+}
+
 #[test]
 fn uds_timeout() {
     struct Echo;